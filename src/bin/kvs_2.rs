@@ -1,5 +1,7 @@
-use clap::{App, AppSettings, Arg, SubCommand};
-use kvs::practice2::{KvStore, KvsError, Result};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use kvs::practice2::{
+    open_engine, KvStore, KvStoreOptions, KvsEngine, KvsError, Result, SyncPolicy,
+};
 use std::env::current_dir;
 use std::process::exit;
 
@@ -11,6 +13,27 @@ fn main() -> Result<()> {
         .setting(AppSettings::DisableHelpSubcommand)
         .setting(AppSettings::SubcommandRequiredElseHelp)
         .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("compaction-threshold")
+                .long("compaction-threshold")
+                .value_name("BYTES")
+                .help("Stale bytes above which the log is compacted")
+                .takes_value(true)
+                .global(true)
+                .validator(|bytes| match bytes.parse::<u64>() {
+                    Ok(bytes) if bytes > 0 => Ok(()),
+                    _ => Err("must be a positive integer".to_owned()),
+                }),
+        )
+        .arg(
+            Arg::with_name("engine")
+                .long("engine")
+                .value_name("ENGINE-NAME")
+                .help("Registered storage engine to use")
+                .takes_value(true)
+                .global(true)
+                .default_value("kvs"),
+        )
         .subcommand(
             SubCommand::with_name("set")
                 .about("Set the value of given key")
@@ -37,12 +60,13 @@ fn main() -> Result<()> {
         ("set", Some(matches)) => {
             let key = matches.value_of("KEY").unwrap();
             let value = matches.value_of("VALUE").unwrap();
-            let mut store = KvStore::open(current_dir()?)?;
+            let mut store = open(matches, options(matches))?;
             store.set(key.to_owned(), value.to_owned())?;
         }
         ("get", Some(matches)) => {
             let key = matches.value_of("KEY").unwrap();
-            let mut store = KvStore::open(current_dir()?)?;
+            let options = options(matches).create_log_on_open(false);
+            let mut store = open(matches, options)?;
             if let Some(value) = store.get(key.to_owned())? {
                 println!("{}", value);
             } else {
@@ -51,7 +75,8 @@ fn main() -> Result<()> {
         }
         ("rm", Some(matches)) => {
             let key = matches.value_of("KEY").unwrap();
-            let mut store = KvStore::open(current_dir()?)?;
+            let options = options(matches).create_log_on_open(false);
+            let mut store = open(matches, options)?;
             match store.remove(key.to_owned()) {
                 Ok(()) => {}
                 Err(KvsError::KeyNotFound) => {
@@ -67,3 +92,26 @@ fn main() -> Result<()> {
     }
     Ok(())
 }
+
+// store options set on the command line
+// every write is synced, as the process exits right after it and a user expects
+// a finished `set` or `rm` to survive a power loss
+fn options(matches: &ArgMatches) -> KvStoreOptions {
+    let options = KvStoreOptions::new().sync_policy(SyncPolicy::EveryWrite);
+    match matches.value_of("compaction-threshold") {
+        Some(bytes) => options.compaction_threshold(bytes.parse().expect("validated by clap")),
+        None => options,
+    }
+}
+
+// open the engine chosen on the command line in the current directory
+// `options` only apply to the built-in `kvs` engine
+fn open(matches: &ArgMatches, options: KvStoreOptions) -> Result<Box<dyn KvsEngine>> {
+    match matches.value_of("engine").expect("engine has a default") {
+        "kvs" => Ok(Box::new(KvStore::open_with_options(
+            current_dir()?,
+            options,
+        )?)),
+        name => open_engine(name, &current_dir()?),
+    }
+}
@@ -1,194 +1,2986 @@
-use serde_json::Deserializer;
-use std::collections::{BTreeMap, HashMap};
+use serde_json::de::IoRead;
+use serde_json::{Deserializer, StreamDeserializer};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::ffi::OsStr;
-use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufReader, BufWriter, Error, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
+use std::iter;
+use std::mem;
+use std::ops::{Bound, Range};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use failure::Fail;
+use log::{debug, info, trace, warn};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
+mod clock;
+#[cfg(feature = "encryption")]
+mod crypto;
+#[cfg(all(feature = "direct-io", target_os = "linux"))]
+mod direct;
+mod files;
+mod frozen;
+mod idle;
+mod memory;
+mod registry;
+mod secondary;
+mod sharded;
+pub use clock::{Clock, MockClock, SystemClock};
+#[cfg(feature = "encryption")]
+use crypto::Cipher;
+pub use files::{FileProvider, LogFile, MemoryFileProvider, StdFileProvider};
+pub use frozen::FrozenKvStore;
+pub use idle::IdleCompactor;
+pub use memory::MemoryStore;
+pub use registry::{engine_names, open_engine, register_engine, EngineFactory};
+pub use secondary::FieldExtractor;
+use secondary::SecondaryIndexes;
+pub use sharded::{HashRing, ShardedKvStore};
+
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+// file holding the settings a store was created with
+const MANIFEST: &str = "MANIFEST";
+// encoding of the commands in the log files
+const LOG_FORMAT: &str = "json";
+// version of the log format written, files of any other version are rejected
+const LOG_VERSION: u16 = 1;
+// header flags, describing how the records of the file were written
+const FLAG_ENCRYPTED: u16 = 1;
+const FLAG_NEWLINE_DELIMITED: u16 = 2;
+const KNOWN_FLAGS: u16 = FLAG_ENCRYPTED | FLAG_NEWLINE_DELIMITED;
+
+// the first object of every log file, a JSON object like the records
+// so that the file stays readable with JSON tools
+#[derive(Serialize, Deserialize)]
+struct LogHeader {
+    kvs_log: u16,
+    flags: u16,
+}
 
 // command/entry type stored in db
-#[derive(Debug, Serialize, Deserialize)]
-enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Command {
+    Set {
+        key: String,
+        value: String,
+        // only written by `KvStore::set_versioned`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<u64>,
+        // milliseconds since the unix epoch, see `KvStoreOptions::track_modified`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        modified: Option<u64>,
+    },
+    Remove {
+        key: String,
+    },
+    // the next `len` sets are applied all or nothing, up to the matching `BatchEnd`
+    BatchStart {
+        len: usize,
+    },
+    BatchEnd,
+    // a value shared by the `SetRef`s of a content-addressed compaction
+    Blob {
+        value: String,
+    },
+    // set `key` to the value of the `Blob` at `pos` with `len` bytes in the same generation
+    SetRef {
+        key: String,
+        pos: u64,
+        len: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        modified: Option<u64>,
+    },
 }
 
 impl Command {
     fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
+        Command::Set {
+            key,
+            value,
+            version: None,
+            modified: None,
+        }
     }
     fn remove(key: String) -> Command {
         Command::Remove { key }
     }
 }
 
+// operations shared by the on-disk `KvStore` and the in-memory `MemoryStore`
+// so code can be written once against either of them
+pub trait KvsEngine {
+    // set a string value of the given key, overwriting an existing one
+    fn set(&mut self, key: String, value: String) -> Result<()>;
+    // get the value of the given key, `None` if it does not exist
+    fn get(&mut self, key: String) -> Result<Option<String>>;
+    // remove the given key, `KvsError::KeyNotFound` if it does not exist
+    fn remove(&mut self, key: String) -> Result<()>;
+    // whether each of the given keys exists, in the same order as `keys`
+    fn contains_keys(&self, keys: &[String]) -> Vec<bool>;
+    // the keys matching a glob pattern with `*` and `?` wildcards, in order
+    // at most `limit` keys if given
+    fn scan_pattern(&mut self, pattern: &str, limit: Option<usize>) -> Result<Vec<String>>;
+    // the entries with `start <= key < end`, in order
+    // at most `limit` entries if given, values past it are not read
+    fn range(
+        &mut self,
+        start: &str,
+        end: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>>;
+}
+
+// summary of a finished compaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionInfo {
+    // stale bytes removed from disk
+    pub reclaimed: u64,
+    // time spent compacting
+    pub duration: Duration,
+}
+
+// estimated heap and buffer bytes held by a store, see `KvStore::memory_usage`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MemoryUsage {
+    // keys and positions of the index
+    pub index: u64,
+    // older versions kept by `KvStoreOptions::keep_versions`
+    pub versions: u64,
+    // modification times kept by `KvStoreOptions::track_modified`
+    pub modified: u64,
+    // generations known and the buffers of the log files open for reading
+    pub readers: u64,
+    // buffer of the log file being written
+    pub writer: u64,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> u64 {
+        self.index + self.versions + self.modified + self.readers + self.writer
+    }
+}
+
+// entries of a page returned by `KvStore::scan_from` with the key the next one starts at
+pub type Page = (Vec<(String, String)>, Option<String>);
+
+// picks the value of a key from the key, the existing and the incoming value
+pub type ConflictResolver = Box<dyn Fn(&str, &str, &str) -> String>;
+
+// what `KvStore::merge_from` does with a key present in both stores
+pub enum ConflictPolicy {
+    // keep the value of the store merged into
+    KeepExisting,
+    // take the value of the store merged from
+    Overwrite,
+    // take the value returned by the resolver
+    Resolve(ConflictResolver),
+}
+
+// when writes are synced to the disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    // only flush to the os, a power loss may lose the latest writes
+    Never,
+    // sync the log after every write before returning
+    EveryWrite,
+}
+
+// what `KvStore::get` does with a value that can't be decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnReadError {
+    // return the error
+    Fail,
+    // log a warning and return `None` as if the key did not exist
+    SkipAsNone,
+}
+
+// how `compact()` chooses the generations to rewrite
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CompactionStrategy {
+    // rewrite every live entry into a single new generation
+    Full,
+    // merge only the generations smaller than `max_size` bytes into a new generation
+    // larger generations are left untouched
+    Minor { max_size: u64 },
+}
+
+// how often a failed log write or flush is retried before the error surfaces
+#[derive(Clone, Copy, Debug, Default)]
+struct RetryPolicy {
+    retries: u32,
+    // delay before the first retry, doubled for every following one
+    base_delay: Duration,
+}
+
+impl RetryPolicy {
+    fn run<T>(&self, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let mut delay = self.base_delay;
+        let mut attempt = 0;
+        loop {
+            match retry_interrupted(&mut op) {
+                Err(e) if attempt < self.retries => {
+                    warn!("log io failed, retrying in {:?}: {}", delay, e);
+                    thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+// how far replaying the logs on open has come
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ReplayProgress {
+    // generation being replayed
+    pub gen: u64,
+    // records replayed so far, over all generations
+    pub records: u64,
+    // bytes replayed so far, over all generations
+    pub bytes: u64,
+}
+
+// callback invoked while the logs are replayed on open
+pub type ReplayProgressCallback = Arc<dyn Fn(ReplayProgress) + Send + Sync>;
+
+// callback invoked before a compaction with the number of stale bytes
+pub type CompactionStartCallback = Arc<dyn Fn(u64) + Send + Sync>;
+// callback invoked after a successful compaction
+pub type CompactionEndCallback = Arc<dyn Fn(CompactionInfo) + Send + Sync>;
+
+// options used when opening a kv store
+#[derive(Clone)]
+pub struct KvStoreOptions {
+    // compact automatically in `set`/`remove` once the stale data exceeds the threshold
+    auto_compact: bool,
+    // stale bytes above which a write triggers an automatic compaction
+    compaction_threshold: u64,
+    compaction_strategy: CompactionStrategy,
+    // upper bound of log files kept open for reading, unbounded if `None`
+    max_open_readers: Option<usize>,
+    // generation of the first log file in an empty directory
+    initial_gen: u64,
+    // create the active log file eagerly instead of on the first write
+    create_log_on_open: bool,
+    // upper bound of bytes per second copied by a compaction
+    compaction_rate_limit: Option<u64>,
+    // directory compactions write their log to before moving it into the store
+    compaction_dir: Option<PathBuf>,
+    // compact the stale data when the store is closed or dropped
+    compact_on_close: bool,
+    // also compact automatically after this many writes
+    compact_every_n_ops: Option<u64>,
+    // also compact automatically once more generations are in use
+    max_generations: Option<usize>,
+    // number of versions kept per key, including the latest one
+    keep_versions: usize,
+    // write every distinct value only once when compacting
+    content_addressed: bool,
+    // treat keys differing only in case as the same key
+    case_insensitive: bool,
+    // retries of failed log writes, none by default
+    retry: RetryPolicy,
+    // fail to open if the options differ from the manifest instead of using the stored ones
+    reject_config_mismatch: bool,
+    on_read_error: OnReadError,
+    // write every record on a line of its own
+    newline_delimited: bool,
+    // record when every key was last set
+    track_modified: bool,
+    sync_policy: SyncPolicy,
+    // glob of the keys indexed on open, all of them if `None`
+    key_filter: Option<String>,
+    // write the logs with `O_DIRECT`, see `KvStoreOptions::direct_io`
+    direct_io: bool,
+    on_compaction_start: Option<CompactionStartCallback>,
+    on_compaction_end: Option<CompactionEndCallback>,
+    // run every given number of records replayed on open
+    on_replay_progress: Option<(u64, ReplayProgressCallback)>,
+    // source of the current time, the system clock by default
+    clock: Arc<dyn Clock>,
+    // opens, creates and removes the files of the store, `std::fs` by default
+    files: Arc<dyn FileProvider>,
+    // encrypts values before they are written to the log
+    #[cfg(feature = "encryption")]
+    cipher: Option<Cipher>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self {
+            auto_compact: true,
+            compaction_threshold: COMPACTION_THRESHOLD,
+            compaction_strategy: CompactionStrategy::Full,
+            max_open_readers: None,
+            initial_gen: 1,
+            create_log_on_open: true,
+            compaction_rate_limit: None,
+            compaction_dir: None,
+            compact_on_close: false,
+            compact_every_n_ops: None,
+            max_generations: None,
+            keep_versions: 1,
+            content_addressed: false,
+            case_insensitive: false,
+            retry: RetryPolicy::default(),
+            reject_config_mismatch: false,
+            on_read_error: OnReadError::Fail,
+            newline_delimited: false,
+            track_modified: false,
+            sync_policy: SyncPolicy::Never,
+            key_filter: None,
+            direct_io: false,
+            on_compaction_start: None,
+            on_compaction_end: None,
+            on_replay_progress: None,
+            clock: Arc::new(SystemClock),
+            files: Arc::new(StdFileProvider),
+            #[cfg(feature = "encryption")]
+            cipher: None,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // enable or disable the inline compaction in `set`/`remove`
+    // when disabled, `compact()` must be called manually
+    pub fn auto_compact(mut self, auto_compact: bool) -> Self {
+        self.auto_compact = auto_compact;
+        self
+    }
+
+    // compact automatically once more than `bytes` of stale data have piled up, 1MB by default
+    pub fn compaction_threshold(mut self, bytes: u64) -> Self {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    // the strategy used by `compact()`, `CompactionStrategy::Full` by default
+    // an automatic compaction that leaves too much stale data falls back to a full one
+    pub fn compaction_strategy(mut self, strategy: CompactionStrategy) -> Self {
+        self.compaction_strategy = strategy;
+        self
+    }
+
+    // keep at most `max` log files open for reading
+    // the least recently used reader is closed and reopened on demand
+    pub fn max_open_readers(mut self, max: usize) -> Self {
+        self.max_open_readers = Some(max.max(1));
+        self
+    }
+
+    // the generation to start from when the directory holds no log yet
+    // ignored if logs already exist, e.g. to continue the numbering of a restored backup
+    pub fn initial_gen(mut self, gen: u64) -> Self {
+        self.initial_gen = gen;
+        self
+    }
+
+    // create the log file of a new active generation right away, `true` by default
+    // when disabled, the file is only created on the first write, so opening
+    // a store without writing to it leaves no empty log file behind
+    pub fn create_log_on_open(mut self, create: bool) -> Self {
+        self.create_log_on_open = create;
+        self
+    }
+
+    // copy at most `bytes_per_sec` bytes per second when compacting
+    // compactions take longer but compete less with other disk users
+    pub fn compaction_rate_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.compaction_rate_limit = Some(bytes_per_sec.max(1));
+        self
+    }
+
+    // compact automatically once `ops` writes have been made since the last compaction
+    // in addition to the stale bytes threshold, whichever is reached first
+    // has no effect if `auto_compact` is disabled
+    pub fn compact_every_n_ops(mut self, ops: u64) -> Self {
+        self.compact_every_n_ops = Some(ops.max(1));
+        self
+    }
+
+    // compact automatically on a write once more than `max` generations are in use,
+    // e.g. after many calls to `rotate`; at least two are allowed since a full
+    // compaction leaves the compacted and the new active generation behind
+    // has no effect if `auto_compact` is disabled
+    pub fn max_generations(mut self, max: usize) -> Self {
+        self.max_generations = Some(max.max(2));
+        self
+    }
+
+    // keep the latest `n` versions of every key instead of only the current one,
+    // readable with `get_version`; older versions are dropped by compactions
+    // a minor compaction could reorder the versions of a key, so `compact()`
+    // always does a full compaction while more than one version is kept
+    pub fn keep_versions(mut self, n: usize) -> Self {
+        self.keep_versions = n.max(1);
+        self
+    }
+
+    // let compactions store every distinct value once, with the keys referring to it
+    // saves space when many keys share a value; like with `keep_versions`, `compact()`
+    // always does a full compaction, which holds the distinct values in memory
+    pub fn content_addressed_compaction(mut self, enabled: bool) -> Self {
+        self.content_addressed = enabled;
+        self
+    }
+
+    // write the log of a compaction to `dir` and move it into the store once complete,
+    // e.g. to keep the scratch writes on a faster disk; the store directory by default
+    // `dir` must be on the same filesystem as the store so that the move is an atomic
+    // rename, otherwise opening fails with `KvsError::CrossDeviceRename`
+    pub fn compaction_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.compaction_dir = Some(dir.into());
+        self
+    }
+
+    // treat keys that only differ in case as the same key, e.g. `Foo` and `foo`
+    // the index holds the lowercase form while the log keeps keys as they were set
+    // recorded in the manifest, so a store keeps the setting it was created with
+    pub fn case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    // compact the store in `close()`, or when it is dropped without being closed
+    // errors of a compaction on drop are only logged, use `close()` to see them
+    pub fn compact_on_close(mut self, compact: bool) -> Self {
+        self.compact_on_close = compact;
+        self
+    }
+
+    // retry a failed write or flush of the log up to `retries` times, waiting
+    // `base_delay` before the first retry and twice as long before each next one
+    // meant for flaky network filesystems, only io errors are retried
+    pub fn retry_policy(mut self, retries: u32, base_delay: Duration) -> Self {
+        self.retry = RetryPolicy {
+            retries,
+            base_delay,
+        };
+        self
+    }
+
+    // how to handle options that differ from the ones recorded in the store's manifest
+    // by default the recorded settings win; if `reject` is set, opening fails with
+    // `KvsError::ConfigMismatch` instead
+    // the log format and encryption can't be overridden and always have to match
+    pub fn reject_config_mismatch(mut self, reject: bool) -> Self {
+        self.reject_config_mismatch = reject;
+        self
+    }
+
+    // what `get` does with a corrupt or undecryptable value, `OnReadError::Fail` by default
+    // io errors always surface
+    pub fn on_read_error(mut self, policy: OnReadError) -> Self {
+        self.on_read_error = policy;
+        self
+    }
+
+    // start every record written to the log with a newline, `false` by default
+    // the logs can then be read line by line with tools like `jq` or `grep`; logs
+    // with and without newlines can be replayed alike, so this can be changed anytime
+    pub fn newline_delimited(mut self, enabled: bool) -> Self {
+        self.newline_delimited = enabled;
+        self
+    }
+
+    // record the time of every set with the entry, `false` by default
+    // the times are taken from the clock with millisecond precision and kept in the
+    // index for `KvStore::keys_modified_between`; keys set while this was
+    // disabled have no time
+    pub fn track_modified(mut self, enabled: bool) -> Self {
+        self.track_modified = enabled;
+        self
+    }
+
+    // when writes are synced to the disk, `SyncPolicy::Never` by default
+    // with `SyncPolicy::EveryWrite`, a write has reached the disk once it returns,
+    // and so has the log of a compaction before it replaces the old ones
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.sync_policy = policy;
+        self
+    }
+
+    // only index the keys matching the glob `pattern` when opening, e.g. `user:*`
+    // for a prefix, to save memory and time on stores of which only a part is needed
+    // the store is a deliberately partial view: the other keys are invisible to
+    // every read, and it is opened read-only since a write or compaction
+    // would lose them
+    pub fn key_filter(mut self, pattern: impl Into<String>) -> Self {
+        self.key_filter = Some(pattern.into());
+        self
+    }
+
+    // write the logs and compactions with direct I/O, bypassing the page cache,
+    // for large sequential loads that would only evict everything else from it
+    // needs the `direct-io` feature on linux and a filesystem supporting `O_DIRECT`,
+    // otherwise opening fails with `KvsError::DirectIoUnsupported`; replaces the
+    // file provider with local files for writing
+    pub fn direct_io(mut self, enabled: bool) -> Self {
+        self.direct_io = enabled;
+        self
+    }
+
+    // use `clock` instead of the system clock wherever the store needs the current time
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    // go through `files` for every file the store opens, creates or removes
+    pub fn file_provider(mut self, files: impl FileProvider + 'static) -> Self {
+        self.files = Arc::new(files);
+        self
+    }
+
+    // register a callback run before every compaction, including automatic ones
+    pub fn on_compaction_start(mut self, f: impl Fn(u64) + Send + Sync + 'static) -> Self {
+        self.on_compaction_start = Some(Arc::new(f));
+        self
+    }
+
+    // register a callback run after every successful compaction, including automatic ones
+    pub fn on_compaction_end(mut self, f: impl Fn(CompactionInfo) + Send + Sync + 'static) -> Self {
+        self.on_compaction_end = Some(Arc::new(f));
+        self
+    }
+
+    // register a callback reporting the replay of the logs on open, e.g. for a progress bar
+    // it runs every `every` records and after each generation
+    pub fn on_replay_progress(
+        mut self,
+        every: u64,
+        f: impl Fn(ReplayProgress) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_replay_progress = Some((every.max(1), Arc::new(f)));
+        self
+    }
+
+    // report the replay progress if a callback is registered
+    fn replay_progress(&self, progress: ReplayProgress) {
+        if let Some((_, f)) = &self.on_replay_progress {
+            f(progress);
+        }
+    }
+
+    // encrypt values at rest with ChaCha20-Poly1305 using the given 256-bit key
+    // reopening with a different key makes `get` fail with `KvsError::DecryptionFailed`
+    #[cfg(feature = "encryption")]
+    pub fn encryption_key(mut self, key: [u8; 32]) -> Self {
+        self.cipher = Some(Cipher::new(&key));
+        self
+    }
+
+    fn encrypted(&self) -> bool {
+        #[cfg(feature = "encryption")]
+        return self.cipher.is_some();
+        #[cfg(not(feature = "encryption"))]
+        false
+    }
+
+    // the header starting each log file written with these options
+    fn log_header(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.encrypted() {
+            flags |= FLAG_ENCRYPTED;
+        }
+        if self.newline_delimited {
+            flags |= FLAG_NEWLINE_DELIMITED;
+        }
+        let header = LogHeader {
+            kvs_log: LOG_VERSION,
+            flags,
+        };
+        serde_json::to_vec(&header).expect("log header not serialized")
+    }
+
+    // check the options against the manifest of an existing store
+    // and take over the recorded settings unless mismatches are rejected
+    fn reconcile(&mut self, manifest: &Manifest) -> Result<()> {
+        let mismatch = |setting: &str| KvsError::ConfigMismatch {
+            setting: setting.to_owned(),
+        };
+        if manifest.format != LOG_FORMAT {
+            return Err(mismatch("format"));
+        }
+        if manifest.encrypted != self.encrypted() {
+            return Err(mismatch("encryption"));
+        }
+        if manifest.auto_compact != self.auto_compact {
+            if self.reject_config_mismatch {
+                return Err(mismatch("auto_compact"));
+            }
+            self.auto_compact = manifest.auto_compact;
+        }
+        if manifest.case_insensitive != self.case_insensitive {
+            if self.reject_config_mismatch {
+                return Err(mismatch("case_insensitive"));
+            }
+            self.case_insensitive = manifest.case_insensitive;
+        }
+        if manifest.compaction_strategy != self.compaction_strategy {
+            if self.reject_config_mismatch {
+                return Err(mismatch("compaction_strategy"));
+            }
+            self.compaction_strategy = manifest.compaction_strategy;
+        }
+        Ok(())
+    }
+
+    // the form of `key` used in the index
+    fn index_key<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        if self.case_insensitive {
+            Cow::Owned(key.to_lowercase())
+        } else {
+            Cow::Borrowed(key)
+        }
+    }
+
+    // whether the key in index form is loaded, see `key_filter`
+    fn loads_key(&self, key: &str) -> bool {
+        self.key_filter
+            .as_ref()
+            .is_none_or(|pattern| glob_match(pattern, key))
+    }
+
+    // like `index_key`, without copying a key that is already in index form
+    fn fold_key(&self, key: String) -> String {
+        if self.case_insensitive {
+            key.to_lowercase()
+        } else {
+            key
+        }
+    }
+
+    // turn a value into the form stored in the log
+    #[allow(unused_variables)]
+    fn encode_value(&self, key: &str, value: String) -> String {
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = &self.cipher {
+            return cipher.encrypt(key, &value);
+        }
+        value
+    }
+
+    // turn a value read from the log back into the one that was set
+    #[allow(unused_variables)]
+    fn decode_value(&self, key: &str, value: String) -> Result<String> {
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = &self.cipher {
+            return cipher.decrypt(key, &value);
+        }
+        Ok(value)
+    }
+}
+
+// settings a store was created with, stored as json in its `MANIFEST` file
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    format: String,
+    encrypted: bool,
+    auto_compact: bool,
+    compaction_strategy: CompactionStrategy,
+    #[serde(default)]
+    case_insensitive: bool,
+    // highest version handed out when records were last dropped from the logs,
+    // which may have held it
+    #[serde(default)]
+    last_version: u64,
+}
+
+impl Manifest {
+    fn new(options: &KvStoreOptions) -> Self {
+        Self {
+            format: LOG_FORMAT.to_owned(),
+            encrypted: options.encrypted(),
+            auto_compact: options.auto_compact,
+            compaction_strategy: options.compaction_strategy,
+            case_insensitive: options.case_insensitive,
+            last_version: 0,
+        }
+    }
+
+    // read the manifest in `dir`, `None` if the store has none yet
+    fn read(files: &dyn FileProvider, dir: &Path) -> Result<Option<Self>> {
+        match files.read(&dir.join(MANIFEST)) {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // write the manifest to `dir`, replacing the file atomically
+    fn write(&self, files: &dyn FileProvider, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join(format!("{}.tmp", MANIFEST));
+        files.write(&tmp_path, &serde_json::to_vec(self)?)?;
+        files.rename(&tmp_path, &dir.join(MANIFEST))?;
+        Ok(())
+    }
+}
+
 // kv store struct
 pub struct KvStore {
     // directory for the data and log
     path: PathBuf,
-    // writer of current log
-    writer: BufWriterWithPos<File>,
+    // writer of current log, `None` until the log file of `current_gen` is created
+    writer: Option<LogWriter>,
+    // reject every write if set
+    read_only: bool,
     // readers map the gen_id to specific file reader
-    readers: HashMap<u64, BufReaderWithPos<File>>,
+    readers: ReaderPool,
     // map command to real position
-    index_map: BTreeMap<String, CommandPos>,
+    // keys are boxed to save the capacity word a `String` would carry per entry
+    index_map: BTreeMap<Box<str>, CommandPos>,
+    // older versions of the keys, see `KvStoreOptions::keep_versions`
+    versions: Versions,
+    // generations written by a content-addressed compaction, holding `SetRef`s
+    deduped_gens: BTreeSet<u64>,
     // the stale data size need be compacted
     uncompacted: u64,
+    // writes made since the last compaction
+    ops_since_compaction: u64,
+    // time of the last write, or of opening the store before the first one
+    last_write: SystemTime,
+    // bytes of keys and values set since the store was opened
+    logical_bytes_written: u64,
+    // bytes appended to the logs since the store was opened, including compactions
+    bytes_written: u64,
+    // highest version handed out by `set_versioned`
+    last_version: u64,
+    // operations served since the store was opened
+    ops: OpCounters,
+    // time each key was last set at, see `KvStoreOptions::track_modified`
+    modified: BTreeMap<Box<str>, SystemTime>,
+    // whether a `compact_shared` is copying entries right now
+    compacting: bool,
+    // indexes by value-derived fields, see `create_secondary_index`
+    secondary: SecondaryIndexes,
     // current gen_id
     current_gen: u64,
+    // options the store was opened with
+    options: KvStoreOptions,
 }
 
 impl KvStore {
     // initial based on specific path
     // it will creat a new one if the path does not exist
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_options(path, KvStoreOptions::default())
+    }
+
+    // initial based on specific path with the given options
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<Self> {
         let path = path.into();
-        fs::create_dir_all(&path)?;
-        let mut readers = HashMap::new();
+        options.files.create_dir_all(&path)?;
+        Self::open_inner(path, options, false, None)
+    }
+
+    // open the store at `path` with the index written by `export_index` instead of
+    // replaying the logs, e.g. for a replica holding copies of the same log files
+    // the index must name exactly the generations present and fit inside their files,
+    // otherwise `KvsError::InvalidIndex` is returned; older versions are not restored
+    pub fn import_index<R: Read>(
+        path: impl Into<PathBuf>,
+        options: KvStoreOptions,
+        index: R,
+    ) -> Result<Self> {
+        let snapshot = serde_json::from_reader(index)?;
+        Self::open_inner(path.into(), options, false, Some(snapshot))
+    }
+
+    // open an existing store for reading only
+    // no directory or log file is created and every write returns `KvsError::ReadOnly`
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_read_only_with_options(path, KvStoreOptions::default())
+    }
+
+    // like `open_read_only`, e.g. with the key of an encrypted store
+    pub fn open_read_only_with_options(
+        path: impl Into<PathBuf>,
+        options: KvStoreOptions,
+    ) -> Result<Self> {
+        Self::open_inner(path.into(), options, true, None)
+    }
+
+    // rebuild a store in `dest` from the log files of a backup directory
+    // every copied file is replayed to validate it; if one fails, the copied
+    // files are removed again and the failing generation is reported
+    pub fn restore(backup: &Path, dest: &Path) -> Result<()> {
+        Self::restore_with_options(backup, dest, KvStoreOptions::default())
+    }
+
+    // like `restore`, with both directories accessed through the file provider of `options`
+    pub fn restore_with_options(backup: &Path, dest: &Path, options: KvStoreOptions) -> Result<()> {
+        let files = &*options.files;
+        files.create_dir_all(dest)?;
+        if !sorted_generation_list(files, dest)?.is_empty() {
+            return Err(KvsError::RestoreTargetNotEmpty);
+        }
+        let mut restored = Vec::new();
+        let res = restore_generations(backup, dest, &options, &mut restored);
+        if res.is_err() {
+            for gen in restored {
+                let _ = files.remove(&log_path(dest, gen));
+            }
+            let _ = files.remove(&dest.join(MANIFEST));
+        }
+        res
+    }
+
+    // fill the empty directory `path` with `entries` and open it, meant for fixtures
+    // all entries go into one generation flushed once at the end, and the index is
+    // built from the written positions instead of replaying the log on open
+    // fails with `KvsError::BulkLoadTargetNotEmpty` if `path` already holds a store
+    pub fn bulk_load(
+        path: impl Into<PathBuf>,
+        entries: impl Iterator<Item = (String, String)>,
+    ) -> Result<KvStore> {
+        let path = path.into();
+        let options = KvStoreOptions::default();
+        options.files.create_dir_all(&path)?;
+        if !sorted_generation_list(&*options.files, &path)?.is_empty() {
+            return Err(KvsError::BulkLoadTargetNotEmpty);
+        }
+        Manifest::new(&options).write(&*options.files, &path)?;
+        let gen = options.initial_gen;
+        let mut writer = BufWriterWithPos::new(options.files.create(&log_path(&path, gen))?)?;
+        writer.write_header(&options.log_header())?;
         let mut index_map = BTreeMap::new();
         let mut uncompacted = 0;
-        let gen_list = sorted_generation_list(&path)?;
-        for &gen in &gen_list {
-            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &mut index_map)?;
-            readers.insert(gen, reader);
+        for (key, value) in entries {
+            let range = writer.write_record(&Command::set(key.clone(), value))?;
+            let cmd_pos: CommandPos = (gen, range).into();
+            if let Some(old_cmd) = index_map.insert(key.into_boxed_str(), cmd_pos) {
+                uncompacted += old_cmd.len;
+            }
         }
-        let current_gen = gen_list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(&path, current_gen, &mut readers)?;
-        Ok(Self {
+        writer.flush()?;
+        let snapshot = IndexSnapshot {
+            gens: vec![gen],
+            index: Cow::Owned(index_map),
+            deduped_gens: BTreeSet::new(),
+            uncompacted,
+            last_version: 0,
+            modified: Cow::Owned(BTreeMap::new()),
+        };
+        Self::open_inner(path, options, false, Some(snapshot))
+    }
+
+    fn open_inner(
+        path: PathBuf,
+        mut options: KvStoreOptions,
+        read_only: bool,
+        snapshot: Option<IndexSnapshot>,
+    ) -> Result<Self> {
+        // a partial view must never write over the keys it doesn't see
+        let read_only = read_only || options.key_filter.is_some();
+        let manifest = Manifest::read(&*options.files, &path)?;
+        if let Some(manifest) = &manifest {
+            options.reconcile(manifest)?;
+        }
+        if options.direct_io && !read_only {
+            options.files = direct_file_provider(&path)?;
+        }
+        let mut readers = ReaderPool::new(
+            path.clone(),
+            options.files.clone(),
+            options.max_open_readers,
+        );
+        let mut replay = Replay::new(options.keep_versions);
+        let gen_list = sorted_generation_list(&*options.files, &path)?;
+        if let Some(snapshot) = snapshot {
+            snapshot.validate(&*options.files, &path, &gen_list)?;
+            for &gen in &gen_list {
+                let reader = BufReaderWithPos::new(options.files.open(&log_path(&path, gen))?)?;
+                readers.insert(gen, reader);
+            }
+            replay.index_map = snapshot.index.into_owned();
+            replay.deduped_gens = snapshot.deduped_gens;
+            replay.uncompacted = snapshot.uncompacted;
+            replay.last_version = snapshot.last_version;
+            replay.modified = snapshot.modified.into_owned();
+            replay.index_map.retain(|key, _| options.loads_key(key));
+            replay.modified.retain(|key, _| options.loads_key(key));
+        } else {
+            replay_logs(&path, &options, &gen_list, &mut replay, &mut readers)?;
+        }
+        if let Some(manifest) = &manifest {
+            replay.last_version = replay.last_version.max(manifest.last_version);
+        }
+        info!(
+            "opened {} with {} keys from {} generations, {} stale bytes{}",
+            path.display(),
+            replay.index_map.len(),
+            gen_list.len(),
+            replay.uncompacted,
+            if read_only { " (read-only)" } else { "" }
+        );
+        let mut store = Self {
             path,
-            writer,
+            writer: None,
+            read_only,
             readers,
-            index_map,
-            uncompacted,
-            current_gen,
-        })
+            index_map: replay.index_map,
+            versions: replay.versions,
+            deduped_gens: replay.deduped_gens,
+            uncompacted: replay.uncompacted,
+            ops_since_compaction: 0,
+            last_write: options.clock.now(),
+            logical_bytes_written: 0,
+            bytes_written: 0,
+            last_version: replay.last_version,
+            modified: replay.modified,
+            ops: OpCounters::default(),
+            compacting: false,
+            secondary: SecondaryIndexes::default(),
+            current_gen: gen_list.last().cloned().unwrap_or(0),
+            options,
+        };
+        if !read_only {
+            if let Some(dir) = &store.options.compaction_dir {
+                store.options.files.create_dir_all(dir)?;
+                if !store.options.files.same_device(dir, &store.path)? {
+                    return Err(KvsError::CrossDeviceRename);
+                }
+            }
+            let gen = match gen_list.last() {
+                Some(&last) => last.checked_add(1).ok_or(KvsError::GenerationOverflow)?,
+                None => store.options.initial_gen,
+            };
+            store.switch_writer(gen)?;
+        }
+        Ok(store)
+    }
+
+    // write the index to `w`, see `import_index`
+    pub fn export_index<W: Write>(&self, w: W) -> Result<()> {
+        let snapshot = IndexSnapshot {
+            gens: self.readers.gens().iter().cloned().collect(),
+            index: Cow::Borrowed(&self.index_map),
+            deduped_gens: self.deduped_gens.clone(),
+            uncompacted: self.uncompacted,
+            last_version: self.last_version,
+            modified: Cow::Borrowed(&self.modified),
+        };
+        serde_json::to_writer(w, &snapshot)?;
+        Ok(())
+    }
+
+    // write the raw log files of the generations from `from_gen` on to `w`, oldest first
+    // returns the highest generation written, 0 if there was none
+    // the stream starts with the number of generations of the store and the list of
+    // them, then the manifest framed by its length, empty if there is none, and every
+    // file framed by its generation and length, all as little endian u64s;
+    // `receive_log` turns the stream back into a store. The active generation may
+    // still grow, so a follow-up stream should start at the returned one again
+    pub fn stream_log<W: Write>(&self, from_gen: u64, mut w: W) -> Result<u64> {
+        let gens = self.readers.gens();
+        w.write_all(&(gens.len() as u64).to_le_bytes())?;
+        for gen in gens {
+            w.write_all(&gen.to_le_bytes())?;
+        }
+        let manifest = match self.options.files.read(&self.path.join(MANIFEST)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            res => res?,
+        };
+        w.write_all(&(manifest.len() as u64).to_le_bytes())?;
+        w.write_all(&manifest)?;
+        let mut last = 0;
+        for &gen in self.readers.gens().range(from_gen..) {
+            let log = self.options.files.read(&log_path(&self.path, gen))?;
+            w.write_all(&gen.to_le_bytes())?;
+            w.write_all(&(log.len() as u64).to_le_bytes())?;
+            w.write_all(&log)?;
+            last = gen;
+        }
+        w.flush()?;
+        Ok(last)
+    }
+
+    // write the log files of a `stream_log` stream into `path`, replacing existing
+    // files of the same generations; the store can be opened there afterwards with
+    // the settings of the source, whose manifest is written once the logs are in
+    // generations in `path` the source no longer has were compacted away and are removed
+    // returns the highest generation received, 0 if there was none
+    pub fn receive_log<R: Read>(path: impl Into<PathBuf>, r: R) -> Result<u64> {
+        Self::receive_log_with_options(path, r, KvStoreOptions::default())
+    }
+
+    // like `receive_log`, writing through the file provider of `options`
+    pub fn receive_log_with_options<R: Read>(
+        path: impl Into<PathBuf>,
+        mut r: R,
+        options: KvStoreOptions,
+    ) -> Result<u64> {
+        let path = path.into();
+        let files = &*options.files;
+        files.create_dir_all(&path)?;
+        let read_u64 = |r: &mut R| -> io::Result<u64> {
+            let mut buf = [0; 8];
+            r.read_exact(&mut buf)?;
+            Ok(u64::from_le_bytes(buf))
+        };
+        let count = read_u64(&mut r)?;
+        let gens = (0..count)
+            .map(|_| read_u64(&mut r))
+            .collect::<io::Result<BTreeSet<_>>>()?;
+        let mut manifest = vec![0; read_u64(&mut r)? as usize];
+        r.read_exact(&mut manifest)?;
+        let mut last = 0;
+        loop {
+            let mut gen = [0; 8];
+            // a clean end of the stream falls between two files
+            let first = loop {
+                match r.read(&mut gen[..1]) {
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    res => break res?,
+                }
+            };
+            if first == 0 {
+                break;
+            }
+            r.read_exact(&mut gen[1..])?;
+            let (gen, len) = (u64::from_le_bytes(gen), read_u64(&mut r)?);
+            let mut log = vec![0; len as usize];
+            r.read_exact(&mut log)?;
+            // a file is either replaced as a whole or left as it was
+            let tmp_path = path.join(format!("{}.log.tmp", gen));
+            files.write(&tmp_path, &log)?;
+            files.rename(&tmp_path, &log_path(&path, gen))?;
+            last = gen;
+        }
+        for gen in sorted_generation_list(files, &path)? {
+            if !gens.contains(&gen) {
+                files.remove(&log_path(&path, gen))?;
+            }
+        }
+        if !manifest.is_empty() {
+            let tmp_path = path.join(format!("{}.tmp", MANIFEST));
+            files.write(&tmp_path, &manifest)?;
+            files.rename(&tmp_path, &path.join(MANIFEST))?;
+        }
+        Ok(last)
     }
 
     // set a string value of the given key
     // if the key exists, the value will be overwritten
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self
-                .index_map
-                .insert(key, (self.current_gen, pos..self.writer.pos).into())
-            {
-                self.uncompacted += old_cmd.len;
+        self.set_inner(key, value, None)
+    }
+
+    // set the given key to the empty string
+    // the key exists afterwards and `get` returns `Some("")`, unlike after `remove`
+    pub fn set_empty(&mut self, key: String) -> Result<()> {
+        self.set(key, String::new())
+    }
+
+    // set the value of the given key, returns the version it was stored with
+    // versions increase over all keys of the store, so a version never comes back
+    // for a key once it was overwritten; values set by `set` have version 0
+    pub fn set_versioned(&mut self, key: String, value: String) -> Result<u64> {
+        let version = self.last_version + 1;
+        self.set_inner(key, value, Some(version))?;
+        self.last_version = version;
+        Ok(version)
+    }
+
+    // get the value of the given key with its version, see `set_versioned`
+    pub fn get_versioned(&mut self, key: String) -> Result<Option<(String, u64)>> {
+        let key = self.options.fold_key(key);
+        if let Some(cmd_pos) = self.index_map.get(key.as_str()) {
+            let reader = self.readers.get_mut(cmd_pos.gen)?;
+            let set = read_set(reader, cmd_pos)?;
+            let value = self.options.decode_value(&key, set.value)?;
+            Ok(Some((value, set.version.unwrap_or(0))))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // set `new` as a new version of `key` if its current version is `expected_version`
+    // returns whether it was set; an absent key never matches
+    pub fn cas_version(&mut self, key: String, expected_version: u64, new: String) -> Result<bool> {
+        match self.get_versioned(key.clone())? {
+            Some((_, version)) if version == expected_version => {
+                self.set_versioned(key, new)?;
+                Ok(true)
             }
+            _ => Ok(false),
         }
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
+    }
+
+    fn set_inner(&mut self, key: String, value: String, version: Option<u64>) -> Result<()> {
+        self.ops.sets += 1;
+        let logical_len = (key.len() + value.len()) as u64;
+        let fields = self.secondary.extract(&value);
+        let value = self
+            .options
+            .encode_value(&self.options.index_key(&key), value);
+        let modified = self.modified_now();
+        let cmd = Command::Set {
+            key,
+            value,
+            version,
+            modified,
+        };
+        let range = self.write_atomic(|store| store.write_command(&cmd))?;
+        self.logical_bytes_written += logical_len;
+        if let Command::Set { key, .. } = cmd {
+            let key = self.options.fold_key(key);
+            record_modified(&mut self.modified, &key, modified);
+            self.secondary.insert(&key, fields);
+            let cmd_pos = (self.current_gen, range).into();
+            self.uncompacted +=
+                insert_version(&mut self.index_map, &mut self.versions, key, cmd_pos);
+        }
+        self.compact_if_needed()?;
+        Ok(())
+    }
+
+    // maintain an index from the field `extract` derives from each value to the
+    // keys holding it, replacing any index of the same name
+    // it is filled from the current values and kept up to date by every write,
+    // but lives in memory only: create it again after opening the store
+    pub fn create_secondary_index<F>(&mut self, name: &str, extract: F) -> Result<()>
+    where
+        F: Fn(&str) -> Option<String> + Send + 'static,
+    {
+        let entries = self.live_values()?;
+        self.secondary.create(name, Box::new(extract), entries);
+        Ok(())
+    }
+
+    // the keys whose value has the field `field_value` in the index `name`, in order
+    // keys are folded in a case-insensitive store
+    pub fn query_secondary(&mut self, name: &str, field_value: &str) -> Result<Vec<String>> {
+        self.secondary
+            .query(name, field_value)
+            .ok_or_else(|| KvsError::UnknownSecondaryIndex(name.to_owned()))
+    }
+
+    // fill the secondary indexes again after the keys changed wholesale
+    fn rebuild_secondary_indexes(&mut self) -> Result<()> {
+        if !self.secondary.is_empty() {
+            let entries = self.live_values()?;
+            for (name, extract) in self.secondary.take() {
+                self.secondary.create(&name, extract, entries.clone());
+            }
         }
         Ok(())
     }
 
+    // every live value by its index key
+    fn live_values(&mut self) -> Result<Vec<(Box<str>, String)>> {
+        let mut entries = Vec::with_capacity(self.index_map.len());
+        for (key, cmd_pos) in &self.index_map {
+            let reader = self.readers.get_mut(cmd_pos.gen)?;
+            let value = read_value(reader, cmd_pos)?;
+            entries.push((key.clone(), self.options.decode_value(key, value)?));
+        }
+        Ok(entries)
+    }
+
     // get the value of given key
     // if the key does not exist, it will return `None`.
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.index_map.get(&key) {
-            let reader = self
-                .readers
-                .get_mut(&cmd_pos.gen)
-                .expect("cannot find log reader");
-            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-            let cmd_reader = reader.take(cmd_pos.len);
-            if let Command::Set { value, .. } = serde_json::from_reader(cmd_reader)? {
-                Ok(Some(value))
-            } else {
-                Err(KvsError::UnexpectedCommandType)
+        self.ops.gets += 1;
+        let key = self.options.fold_key(key);
+        if let Some(cmd_pos) = self.index_map.get(key.as_str()) {
+            let reader = self.readers.get_mut(cmd_pos.gen)?;
+            let value = read_value(reader, cmd_pos)
+                .and_then(|value| self.options.decode_value(&key, value));
+            match value {
+                Err(e)
+                    if is_decode_error(&e)
+                        && self.options.on_read_error == OnReadError::SkipAsNone =>
+                {
+                    warn!(
+                        "skipping unreadable value of {} at {:?}: {}",
+                        key, cmd_pos, e
+                    );
+                    Ok(None)
+                }
+                value => value.map(Some),
             }
         } else {
             Ok(None)
         }
     }
 
-    // remove the given key
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        if self.index_map.contains_key(&key) {
-            let cmd = Command::remove(key);
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
-            if let Command::Remove { key } = cmd {
-                let old_cmd = self.index_map.remove(&key).expect("Key not found");
-                self.uncompacted += old_cmd.len;
-            }
-            Ok(())
-        } else {
-            Err(KvsError::KeyNotFound)
-        }
-    }
+    // write all live entries to `dest` as a sorted, immutable `FrozenKvStore`
+    // keys are written in index form and values decrypted; the file is written
+    // next to `dest` first and moved there once complete
+    pub fn freeze(&mut self, dest: &Path) -> Result<()> {
+        let mut tmp_name = dest.file_name().unwrap_or_default().to_owned();
+        tmp_name.push(".tmp");
+        let tmp_path = dest.with_file_name(tmp_name);
+        let mut writer = BufWriter::new(self.options.files.create(&tmp_path)?);
+        let readers = &mut self.readers;
+        let options = &self.options;
+        let entries = self.index_map.iter().map(|(key, cmd_pos)| {
+            let reader = readers.get_mut(cmd_pos.gen)?;
+            let value = read_value(reader, cmd_pos)?;
+            Ok((key.to_string(), options.decode_value(key, value)?))
+        });
+        frozen::write(&mut writer, entries)?;
+        writer.flush()?;
+        drop(writer);
+        self.options.files.rename(&tmp_path, dest)?;
+        info!(
+            "froze {} keys of {} into {}",
+            self.index_map.len(),
+            self.path.display(),
+            dest.display()
+        );
+        Ok(())
+    }
+
+    // `(gen, pos, len)` of the record currently holding the given key, without reading it
+    pub fn locate(&self, key: &str) -> Option<(u64, u64, u64)> {
+        self.index_map
+            .get(&*self.options.index_key(key))
+            .map(|cmd_pos| (cmd_pos.gen, cmd_pos.pos, cmd_pos.len))
+    }
+
+    // get the value of the given key deserialized from JSON
+    pub fn get_as<T: DeserializeOwned>(&mut self, key: &str) -> Result<Option<T>> {
+        match self.get(key.to_owned())? {
+            Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    // set the given key to `value` serialized as JSON
+    pub fn set_as<T: Serialize>(&mut self, key: String, value: &T) -> Result<()> {
+        let value = serde_json::to_string(value)?;
+        self.set(key, value)
+    }
+
+    // get the latest value set for the given key in one generation, ignoring the index
+    // this reads the physical history, e.g. where a key lived before a compaction moved it
+    pub fn get_at_gen(&mut self, key: &str, gen: u64) -> Result<Option<String>> {
+        if !self.readers.gens().contains(&gen) {
+            return Err(KvsError::GenerationNotFound(gen));
+        }
+        let key = self.options.index_key(key);
+        let reader = self.readers.get_mut(gen)?;
+        read_header(reader)?;
+        let mut latest = None;
+        for cmd in Deserializer::from_reader(&mut *reader).into_iter::<Command>() {
+            let cmd = match cmd {
+                Ok(cmd) => cmd,
+                Err(e) if e.is_eof() => break,
+                Err(e) => return Err(e.into()),
+            };
+            match &cmd {
+                Command::Set { key: set_key, .. } | Command::SetRef { key: set_key, .. }
+                    if self.options.index_key(set_key) == key =>
+                {
+                    latest = Some(cmd)
+                }
+                _ => {}
+            }
+        }
+        let value = match latest {
+            Some(Command::Set { value, .. }) => value,
+            Some(Command::SetRef { pos, len, .. }) => read_blob(reader, pos, len)?,
+            _ => return Ok(None),
+        };
+        self.options.decode_value(&key, value).map(Some)
+    }
+
+    // start a read transaction that keeps seeing the store as it is now
+    // it copies the whole index, so it costs memory in proportion to the number of keys,
+    // and keeps every current log file open until it is dropped
+    pub fn read_tx(&self) -> Result<ReadTxn> {
+        let mut readers = HashMap::new();
+        for &gen in self.readers.gens() {
+            let reader =
+                BufReaderWithPos::new(self.options.files.open(&log_path(&self.path, gen))?)?;
+            readers.insert(gen, reader);
+        }
+        Ok(ReadTxn {
+            index_map: self.index_map.clone(),
+            readers,
+            options: self.options.clone(),
+        })
+    }
+
+    // whether each of the given keys exists, in the same order as `keys`
+    pub fn contains_keys(&self, keys: &[String]) -> Vec<bool> {
+        keys.iter()
+            .map(|key| self.index_map.contains_key(&*self.options.index_key(key)))
+            .collect()
+    }
+
+    // list the keys matching a glob pattern in order, `*` matches any run of characters
+    // and `?` a single one; only the keys sharing the literal prefix before the first
+    // wildcard are visited
+    // keys are returned as they were set, also in a case-insensitive store
+    // with `limit`, the scan stops after that many matches
+    pub fn scan_pattern(&mut self, pattern: &str, limit: Option<usize>) -> Result<Vec<String>> {
+        let pattern = self.options.index_key(pattern).into_owned();
+        let prefix = pattern
+            .find(['*', '?'])
+            .map_or(&*pattern, |wildcard| &pattern[..wildcard]);
+        let mut keys = Vec::new();
+        for (key, cmd_pos) in self
+            .index_map
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .filter(|(key, _)| glob_match(&pattern, key))
+            .take(limit.unwrap_or(usize::MAX))
+        {
+            // only a folded key differs from the one it was set with
+            keys.push(if self.options.case_insensitive {
+                read_entry(self.readers.get_mut(cmd_pos.gen)?, cmd_pos)?.0
+            } else {
+                key.to_string()
+            });
+        }
+        Ok(keys)
+    }
+
+    // the entries with `start <= key < end` in ascending key order
+    // keys are returned as they were set, also in a case-insensitive store
+    // with `limit`, only the first that many entries are read
+    pub fn range(
+        &mut self,
+        start: &str,
+        end: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        let (start, end) = (self.options.index_key(start), self.options.index_key(end));
+        if start >= end {
+            return Ok(Vec::new());
+        }
+        let mut entries = Vec::new();
+        for (key, cmd_pos) in self
+            .index_map
+            .range::<str, _>((Bound::Included(&*start), Bound::Excluded(&*end)))
+            .take(limit.unwrap_or(usize::MAX))
+        {
+            let reader = self.readers.get_mut(cmd_pos.gen)?;
+            let (set_key, value) = read_entry(reader, cmd_pos)?;
+            entries.push((set_key, self.options.decode_value(key, value)?));
+        }
+        Ok(entries)
+    }
+
+    // one page of at most `limit` entries in ascending key order, starting at the key
+    // `start` or after it, or at the first key if `None`
+    // returns the entries and the key the next page starts at, `None` after the last page
+    pub fn scan_from(&mut self, start: Option<String>, limit: usize) -> Result<Page> {
+        let start = start.map(|start| self.options.fold_key(start));
+        let lower = start.as_deref().map_or(Bound::Unbounded, Bound::Included);
+        let mut entries = Vec::new();
+        for (key, cmd_pos) in self.index_map.range::<str, _>((lower, Bound::Unbounded)) {
+            if entries.len() == limit {
+                return Ok((entries, Some(key.to_string())));
+            }
+            let reader = self.readers.get_mut(cmd_pos.gen)?;
+            let (set_key, value) = read_entry(reader, cmd_pos)?;
+            entries.push((set_key, self.options.decode_value(key, value)?));
+        }
+        Ok((entries, None))
+    }
+
+    // get the values of several keys, in the same order as `keys`
+    // the entries are read sorted by their position in the logs to save seeks
+    pub fn get_many(&mut self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        self.ops.gets += keys.len() as u64;
+        let index_map = &self.index_map;
+        let mut found = keys
+            .iter()
+            .enumerate()
+            .filter_map(|(i, key)| Some((i, index_map.get(&*self.options.index_key(key))?)))
+            .collect::<Vec<_>>();
+        found.sort_unstable_by_key(|(_, cmd_pos)| (cmd_pos.gen, cmd_pos.pos));
+        let mut values = vec![None; keys.len()];
+        for (i, cmd_pos) in found {
+            let reader = self.readers.get_mut(cmd_pos.gen)?;
+            let value = read_value(reader, cmd_pos)?;
+            let key = self.options.index_key(&keys[i]);
+            values[i] = Some(self.options.decode_value(&key, value)?);
+        }
+        Ok(values)
+    }
+
+    // get a version of the given key, `0` being the current value and `1` the one before
+    // only the versions configured with `KvStoreOptions::keep_versions` are available
+    pub fn get_version(&mut self, key: &str, n: usize) -> Result<Option<String>> {
+        let key = self.options.index_key(key);
+        let cmd_pos = match n {
+            0 => self.index_map.get(&*key),
+            n => self.versions.get(&key, n),
+        };
+        if let Some(cmd_pos) = cmd_pos {
+            let reader = self.readers.get_mut(cmd_pos.gen)?;
+            let value = read_value(reader, cmd_pos)?;
+            Ok(Some(self.options.decode_value(&key, value)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // iterate over all entries in ascending key order
+    // keys are returned as they were set, also in a case-insensitive store
+    pub fn iter(&mut self) -> impl Iterator<Item = Result<(String, String)>> + '_ {
+        let readers = &mut self.readers;
+        let options = &self.options;
+        self.index_map.iter().map(move |(key, cmd_pos)| {
+            let reader = readers.get_mut(cmd_pos.gen)?;
+            let (set_key, value) = read_entry(reader, cmd_pos)?;
+            Ok((set_key, options.decode_value(key, value)?))
+        })
+    }
+
+    // the entries whose value satisfies `pred`, in ascending key order
+    pub fn filter_values<F: Fn(&str) -> bool>(&mut self, pred: F) -> Result<Vec<(String, String)>> {
+        let mut entries = Vec::new();
+        for entry in self.iter() {
+            let (key, value) = entry?;
+            if pred(&value) {
+                entries.push((key, value));
+            }
+        }
+        Ok(entries)
+    }
+
+    // iterate over all entries in descending key order
+    // keys are returned as they were set, also in a case-insensitive store
+    pub fn iter_rev(&mut self) -> impl Iterator<Item = Result<(String, String)>> + '_ {
+        let readers = &mut self.readers;
+        let options = &self.options;
+        self.index_map.iter().rev().map(move |(key, cmd_pos)| {
+            let reader = readers.get_mut(cmd_pos.gen)?;
+            let (set_key, value) = read_entry(reader, cmd_pos)?;
+            Ok((set_key, options.decode_value(key, value)?))
+        })
+    }
+
+    // append `suffix` to the value of the given key and return the new length
+    // an absent key is treated as an empty value
+    pub fn append(&mut self, key: String, suffix: &str) -> Result<usize> {
+        let mut value = self.get(key.clone())?.unwrap_or_default();
+        value.push_str(suffix);
+        let len = value.len();
+        self.set(key, value)?;
+        Ok(len)
+    }
+
+    // remove the given key
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.ops.removes += 1;
+        if self.index_map.contains_key(&*self.options.index_key(&key)) {
+            let cmd = Command::remove(key);
+            let range = self.write_atomic(|store| store.write_command(&cmd))?;
+            if let Command::Remove { key } = cmd {
+                let key = self.options.fold_key(key);
+                let old_cmd = self.index_map.remove(key.as_str()).expect("Key not found");
+                self.modified.remove(key.as_str());
+                self.secondary.remove(&key);
+                // the removed entry with its older versions and the tombstone itself are stale
+                self.uncompacted +=
+                    old_cmd.len + self.versions.remove(&key) + range.end - range.start;
+            }
+            self.compact_if_needed()?;
+            Ok(())
+        } else {
+            Err(KvsError::KeyNotFound)
+        }
+    }
+
+    // remove the key only if its current value equals `expected`, returning whether it was removed
+    // nothing is written when the value differs or the key is absent
+    pub fn remove_if(&mut self, key: &str, expected: &str) -> Result<bool> {
+        match self.get(key.to_owned())? {
+            Some(value) if value == expected => {
+                self.remove(key.to_owned())?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    // set several keys at once, all or nothing
+    // the sets are framed as one batch, so a crash while writing it leaves
+    // none of them visible after reopening
+    pub fn set_transactional(&mut self, entries: Vec<(String, String)>) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        self.ops.sets += entries.len() as u64;
+        let logical_len = entries
+            .iter()
+            .map(|(key, value)| (key.len() + value.len()) as u64)
+            .sum::<u64>();
+        let modified = self.modified_now();
+        let fields = entries
+            .iter()
+            .map(|(_, value)| self.secondary.extract(value))
+            .collect::<Vec<_>>();
+        let (start_range, ranges, end_range) = self.write_atomic(|store| {
+            let start_range = store.write_command(&Command::BatchStart { len: entries.len() })?;
+            let mut ranges = Vec::with_capacity(entries.len());
+            for (key, value) in entries {
+                let index_key = store.options.fold_key(key.clone());
+                let value = store.options.encode_value(&index_key, value);
+                let range = store.write_command(&Command::Set {
+                    key,
+                    value,
+                    version: None,
+                    modified,
+                })?;
+                ranges.push((index_key, range));
+            }
+            let end_range = store.write_command(&Command::BatchEnd)?;
+            Ok((start_range, ranges, end_range))
+        })?;
+        self.logical_bytes_written += logical_len;
+
+        self.uncompacted += start_range.end - start_range.start + end_range.end - end_range.start;
+        for ((key, range), fields) in ranges.into_iter().zip(fields) {
+            record_modified(&mut self.modified, &key, modified);
+            self.secondary.insert(&key, fields);
+            let cmd_pos = (self.current_gen, range).into();
+            self.uncompacted +=
+                insert_version(&mut self.index_map, &mut self.versions, key, cmd_pos);
+        }
+        self.compact_if_needed()?;
+        Ok(())
+    }
+
+    // copy every live key of `other` into this store, returns the number of keys written
+    // `on_conflict` decides about the keys present in both; all writes go into
+    // one batch, so a crash leaves either all or none of them
+    pub fn merge_from(
+        &mut self,
+        other: &mut KvStore,
+        on_conflict: ConflictPolicy,
+    ) -> Result<usize> {
+        let incoming = other.iter().collect::<Result<Vec<_>>>()?;
+        let mut entries = Vec::with_capacity(incoming.len());
+        for (key, value) in incoming {
+            let value = match self.get(key.clone())? {
+                None => value,
+                Some(existing) => match &on_conflict {
+                    ConflictPolicy::KeepExisting => continue,
+                    ConflictPolicy::Overwrite => value,
+                    ConflictPolicy::Resolve(resolve) => resolve(&key, &existing, &value),
+                },
+            };
+            entries.push((key, value));
+        }
+        let merged = entries.len();
+        self.set_transactional(entries)?;
+        Ok(merged)
+    }
+
+    // exchange the values of two existing keys, `KvsError::KeyNotFound` if either is absent
+    // both new entries are written as one batch, so a crash in between leaves
+    // the old values in place after reopening
+    pub fn swap(&mut self, a: String, b: String) -> Result<()> {
+        let value_a = self.get(a.clone())?.ok_or(KvsError::KeyNotFound)?;
+        let value_b = self.get(b.clone())?.ok_or(KvsError::KeyNotFound)?;
+        if self.options.index_key(&a) == self.options.index_key(&b) {
+            return Ok(());
+        }
+        self.set_transactional(vec![(a, value_b), (b, value_a)])
+    }
+
+    // move the value of `from` to `to`, overwriting `to` if it exists
+    // the new entry is written before the tombstone of `from`, so a crash
+    // in between never loses the value
+    pub fn rename(&mut self, from: String, to: String) -> Result<()> {
+        let value = self.get(from.clone())?.ok_or(KvsError::KeyNotFound)?;
+        if self.options.index_key(&from) == self.options.index_key(&to) {
+            return Ok(());
+        }
+        let logical_len = (to.len() + value.len()) as u64;
+        let fields = self.secondary.extract(&value);
+        let value = self
+            .options
+            .encode_value(&self.options.index_key(&to), value);
+        let modified = self.modified_now();
+        let (set_range, remove_range) = self.write_atomic(|store| {
+            let set_range = store.write_command(&Command::Set {
+                key: to.clone(),
+                value,
+                version: None,
+                modified,
+            })?;
+            let remove_range = store.write_command(&Command::remove(from.clone()))?;
+            Ok((set_range, remove_range))
+        })?;
+        self.logical_bytes_written += logical_len;
 
-    // clear stale data in the log
-    pub fn compact(&mut self) -> Result<()> {
-        let compaction_gen = self.current_gen + 1;
-        self.current_gen += 2;
-        self.writer = self.new_log_file(self.current_gen)?;
+        let (from, to) = (self.options.fold_key(from), self.options.fold_key(to));
+        record_modified(&mut self.modified, &to, modified);
+        self.modified.remove(from.as_str());
+        self.secondary.remove(&from);
+        self.secondary.insert(&to, fields);
+        let cmd_pos = (self.current_gen, set_range).into();
+        self.uncompacted += insert_version(&mut self.index_map, &mut self.versions, to, cmd_pos);
+        let old_cmd = self.index_map.remove(from.as_str()).expect("Key not found");
+        self.uncompacted +=
+            old_cmd.len + self.versions.remove(&from) + remove_range.end - remove_range.start;
+        self.compact_if_needed()?;
+        Ok(())
+    }
+
+    // clear stale data in the log with the configured strategy
+    // returns the number of stale bytes reclaimed
+    pub fn compact(&mut self) -> Result<u64> {
+        self.compact_with(self.options.compaction_strategy)
+    }
 
-        let mut writer = self.new_log_file(compaction_gen)?;
-        let mut new_pos = 0;
+    // clear stale data in the log with the given strategy
+    pub fn compact_with(&mut self, strategy: CompactionStrategy) -> Result<u64> {
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        if self.compacting {
+            return Err(KvsError::CompactionInProgress);
+        }
+        info!(
+            "{:?} compaction started with {} stale bytes",
+            strategy, self.uncompacted
+        );
+        if let Some(on_start) = &self.options.on_compaction_start {
+            on_start(self.uncompacted);
+        }
+        let strategy = if self.options.keep_versions > 1 || self.options.content_addressed {
+            CompactionStrategy::Full
+        } else {
+            strategy
+        };
+        let start = self.options.clock.now();
+        let reclaimed = match strategy {
+            CompactionStrategy::Full => self.compact_full()?,
+            CompactionStrategy::Minor { max_size } => self.compact_minor(max_size)?,
+        };
+        self.ops_since_compaction = 0;
+        // a clock moved backwards counts as no time spent
+        let duration = self
+            .options
+            .clock
+            .now()
+            .duration_since(start)
+            .unwrap_or_default();
+        info!(
+            "{:?} compaction reclaimed {} bytes in {:?}",
+            strategy, reclaimed, duration
+        );
+        if let Some(on_end) = &self.options.on_compaction_end {
+            on_end(CompactionInfo {
+                reclaimed,
+                duration,
+            });
+        }
+        Ok(reclaimed)
+    }
+
+    fn compact_full(&mut self) -> Result<u64> {
+        let compaction_gen = self.next_gen(1)?;
+        self.switch_writer(self.next_gen(2)?)?;
+
+        let mut writer = self.compaction_writer(compaction_gen)?;
+        let mut throttle = Throttle::new(self.options.compaction_rate_limit);
+        // the blob written for every distinct value so far
+        let mut blobs = self.options.content_addressed.then(HashMap::new);
+        // the index is only pointed at the copies once the new log is in place
+        let mut moved = Vec::with_capacity(self.index_map.len());
+        for (key, cmd_pos) in &self.index_map {
+            // older versions are copied first so that replaying the log restores their order
+            if let Some(older) = self.versions.older.get(key) {
+                for old_pos in older.iter().rev() {
+                    moved.push(copy_entry(
+                        &mut self.readers,
+                        &self.deduped_gens,
+                        &mut writer,
+                        compaction_gen,
+                        old_pos,
+                        blobs.as_mut(),
+                        &mut throttle,
+                    )?);
+                }
+            }
+            moved.push(copy_entry(
+                &mut self.readers,
+                &self.deduped_gens,
+                &mut writer,
+                compaction_gen,
+                cmd_pos,
+                blobs.as_mut(),
+                &mut throttle,
+            )?);
+        }
+        self.install_compaction_log(compaction_gen, writer)?;
+        if blobs.is_some() {
+            self.deduped_gens.insert(compaction_gen);
+        }
+
+        let mut moved = moved.into_iter();
+        for (key, cmd_pos) in self.index_map.iter_mut() {
+            if let Some(older) = self.versions.older.get_mut(key) {
+                for old_pos in older.iter_mut().rev() {
+                    *old_pos = moved.next().expect("compacted version not found");
+                }
+            }
+            *cmd_pos = moved.next().expect("compacted entry not found");
+        }
+        let stales_gens = self
+            .readers
+            .gens()
+            .range(..compaction_gen)
+            .cloned()
+            .collect::<Vec<_>>();
+        for gen in stales_gens {
+            self.readers.remove(gen);
+            self.deduped_gens.remove(&gen);
+            self.options.files.remove(&log_path(&self.path, gen))?;
+        }
+        let reclaimed = self.uncompacted;
+        self.uncompacted = 0;
+        Ok(reclaimed)
+    }
+
+    // compact fully while passing every live entry through `transform`, which returns
+    // the key and value to write instead or `None` to drop the entry, e.g. to rename
+    // keys or migrate the format of values in one pass
+    // `transform` sees keys as they were set; when several entries end up with the
+    // same key, the last one in key order wins. Older versions are dropped, and keys
+    // dropped or renamed away are removed in the new log, so a crash before the old
+    // logs are removed still leaves only the new entries
+    pub fn compact_with_transform<F>(&mut self, mut transform: F) -> Result<()>
+    where
+        F: FnMut(&str, String) -> Option<(String, String)>,
+    {
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        if self.compacting {
+            return Err(KvsError::CompactionInProgress);
+        }
+        info!("transforming compaction started for {} keys", self.len());
+        if let Some(on_start) = &self.options.on_compaction_start {
+            on_start(self.uncompacted);
+        }
+        let start = self.options.clock.now();
+        let compaction_gen = self.next_gen(1)?;
+        self.switch_writer(self.next_gen(2)?)?;
+
+        let mut writer = self.compaction_writer(compaction_gen)?;
+        let mut throttle = Throttle::new(self.options.compaction_rate_limit);
+        let mut index_map = BTreeMap::new();
+        let mut modified = BTreeMap::new();
+        // bytes of entries overwritten by a later one with the same new key
+        let mut uncompacted = 0;
+        for (key, cmd_pos) in &self.index_map {
+            let reader = self.readers.get_mut(cmd_pos.gen)?;
+            let set = read_set(reader, cmd_pos)?;
+            let value = self.options.decode_value(key, set.value)?;
+            let (new_key, new_value) = match transform(&set.key, value) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let index_key = self.options.fold_key(new_key.clone());
+            let range = writer.write_record(&Command::Set {
+                key: new_key,
+                value: self.options.encode_value(&index_key, new_value),
+                version: set.version,
+                modified: set.modified,
+            })?;
+            throttle.consume(range.end - range.start);
+            record_modified(&mut modified, &index_key, set.modified);
+            let new_pos: CommandPos = (compaction_gen, range).into();
+            if let Some(old_pos) = index_map.insert(index_key.into_boxed_str(), new_pos) {
+                uncompacted += old_pos.len;
+            }
+        }
+        // the old logs still hold the keys that are gone, until they are removed
+        for key in self.index_map.keys() {
+            if !index_map.contains_key(key) {
+                let range = writer.write_record(&Command::remove(key.to_string()))?;
+                uncompacted += range.end - range.start;
+            }
+        }
+        self.install_compaction_log(compaction_gen, writer)?;
+
+        let stales_gens = self
+            .readers
+            .gens()
+            .range(..compaction_gen)
+            .cloned()
+            .collect::<Vec<_>>();
+        for gen in stales_gens {
+            self.readers.remove(gen);
+            self.deduped_gens.remove(&gen);
+            self.options.files.remove(&log_path(&self.path, gen))?;
+        }
+        self.index_map = index_map;
+        self.versions.older.clear();
+        self.modified = modified;
+        let reclaimed = std::mem::replace(&mut self.uncompacted, uncompacted);
+        self.ops_since_compaction = 0;
+        self.rebuild_secondary_indexes()?;
+        // a clock moved backwards counts as no time spent
+        let duration = self
+            .options
+            .clock
+            .now()
+            .duration_since(start)
+            .unwrap_or_default();
+        info!("transforming compaction left {} keys", self.len());
+        if let Some(on_end) = &self.options.on_compaction_end {
+            on_end(CompactionInfo {
+                reclaimed,
+                duration,
+            });
+        }
+        Ok(())
+    }
+
+    // compact `store` without holding its lock while the live entries are copied,
+    // so other threads keep reading and writing in the meantime
+    // the lock is only taken to start, moving writes to a new generation, and to
+    // finish, pointing the keys not written since at their copies; any other
+    // compaction, `clear` or `refresh` in between fails with
+    // `KvsError::CompactionInProgress` and inline compactions are skipped
+    // a store keeping older versions or compacting content-addressed is compacted
+    // under the lock like with `compact()`
+    pub fn compact_shared(store: &Mutex<KvStore>) -> Result<u64> {
+        let mut job = {
+            let mut store = store.lock().unwrap();
+            if store.options.keep_versions > 1 || store.options.content_addressed {
+                return store.compact();
+            }
+            store.begin_shared_compaction()?
+        };
+        let copied = job.copy();
+        store.lock().unwrap().finish_shared_compaction(job, copied)
+    }
+
+    fn begin_shared_compaction(&mut self) -> Result<SharedCompaction> {
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        if self.compacting {
+            return Err(KvsError::CompactionInProgress);
+        }
+        info!(
+            "shared compaction started with {} stale bytes",
+            self.uncompacted
+        );
+        if let Some(on_start) = &self.options.on_compaction_start {
+            on_start(self.uncompacted);
+        }
+        let start = self.options.clock.now();
+        let (gen, active_gen) = (self.next_gen(1)?, self.next_gen(2)?);
+        self.switch_writer(active_gen)?;
+
+        // readers of its own, so the copying doesn't need the store
+        let mut readers = ReaderPool::new(
+            self.path.clone(),
+            self.options.files.clone(),
+            self.options.max_open_readers,
+        );
+        let mut old_size = 0;
+        for &old_gen in self.readers.gens().range(..gen) {
+            let path = log_path(&self.path, old_gen);
+            readers.insert(
+                old_gen,
+                BufReaderWithPos::new(self.options.files.open(&path)?)?,
+            );
+            old_size += self.options.files.len(&path)?;
+        }
+        let job = SharedCompaction {
+            gen,
+            entries: self
+                .index_map
+                .iter()
+                .map(|(key, cmd_pos)| (key.clone(), *cmd_pos))
+                .collect(),
+            readers,
+            deduped_gens: self.deduped_gens.clone(),
+            writer: self.compaction_writer(gen)?,
+            throttle: Throttle::new(self.options.compaction_rate_limit),
+            old_size,
+            start,
+        };
+        self.compacting = true;
+        Ok(job)
+    }
+
+    fn finish_shared_compaction(
+        &mut self,
+        job: SharedCompaction,
+        copied: Result<Vec<CommandPos>>,
+    ) -> Result<u64> {
+        self.compacting = false;
+        let moved = match copied {
+            Ok(moved) => moved,
+            Err(e) => {
+                drop(job.writer);
+                if let Err(remove_err) = self.options.files.remove(&self.compaction_path(job.gen)) {
+                    warn!("failed to remove unfinished compaction log: {}", remove_err);
+                }
+                return Err(e);
+            }
+        };
+        self.install_compaction_log(job.gen, job.writer)?;
+
+        // keys written since the start still point at newer generations,
+        // their copies are stale already
+        let (mut live, mut stale_copies) = (0, 0);
+        for ((key, old_pos), new_pos) in job.entries.into_iter().zip(moved) {
+            match self.index_map.get_mut(&key) {
+                Some(cmd_pos) if *cmd_pos == old_pos => {
+                    live += old_pos.len;
+                    *cmd_pos = new_pos;
+                }
+                _ => stale_copies += new_pos.len,
+            }
+        }
+        let stales_gens = self
+            .readers
+            .gens()
+            .range(..job.gen)
+            .cloned()
+            .collect::<Vec<_>>();
+        for gen in stales_gens {
+            self.readers.remove(gen);
+            self.deduped_gens.remove(&gen);
+            self.options.files.remove(&log_path(&self.path, gen))?;
+        }
+        let removed = job.old_size.saturating_sub(live);
+        self.uncompacted = self.uncompacted.saturating_sub(removed) + stale_copies;
+        self.ops_since_compaction = 0;
+
+        let reclaimed = removed.saturating_sub(stale_copies);
+        // a clock moved backwards counts as no time spent
+        let duration = self
+            .options
+            .clock
+            .now()
+            .duration_since(job.start)
+            .unwrap_or_default();
+        info!(
+            "shared compaction reclaimed {} bytes in {:?}",
+            reclaimed, duration
+        );
+        if let Some(on_end) = &self.options.on_compaction_end {
+            on_end(CompactionInfo {
+                reclaimed,
+                duration,
+            });
+        }
+        Ok(reclaimed)
+    }
+
+    fn compact_minor(&mut self, max_size: u64) -> Result<u64> {
+        let mut small_gens = BTreeSet::new();
+        let mut small_size = 0;
+        for &gen in self.readers.gens() {
+            let size = self.options.files.len(&log_path(&self.path, gen))?;
+            if size < max_size {
+                small_gens.insert(gen);
+                small_size += size;
+            }
+        }
+        if small_gens.is_empty() {
+            return Ok(0);
+        }
+        // tombstones must survive while an untouched generation may still hold the removed key
+        let keep_tombstones = self
+            .readers
+            .gens()
+            .iter()
+            .any(|gen| !small_gens.contains(gen));
+
+        let compaction_gen = self.next_gen(1)?;
+        self.switch_writer(self.next_gen(2)?)?;
+
+        let mut writer = self.compaction_writer(compaction_gen)?;
+        let mut throttle = Throttle::new(self.options.compaction_rate_limit);
+        let mut moved = Vec::new();
+        for cmd_pos in self.index_map.values() {
+            if small_gens.contains(&cmd_pos.gen) {
+                moved.push(copy_entry(
+                    &mut self.readers,
+                    &self.deduped_gens,
+                    &mut writer,
+                    compaction_gen,
+                    cmd_pos,
+                    None,
+                    &mut throttle,
+                )?);
+            }
+        }
+
+        if keep_tombstones {
+            let mut removed = BTreeSet::new();
+            for gen in &small_gens {
+                removed.extend(self.removed_keys(*gen)?);
+            }
+            for key in removed {
+                writer.write_record(&Command::remove(key))?;
+            }
+        }
+
+        let compacted_size = writer.pos;
+        self.install_compaction_log(compaction_gen, writer)?;
+
+        let mut moved = moved.into_iter();
         for cmd_pos in self.index_map.values_mut() {
-            let reader = self
-                .readers
-                .get_mut(&cmd_pos.gen)
-                .expect("Cannot find log reader");
-            if reader.pos != cmd_pos.pos {
-                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+            if small_gens.contains(&cmd_pos.gen) {
+                *cmd_pos = moved.next().expect("compacted entry not found");
+            }
+        }
+        for gen in small_gens {
+            self.readers.remove(gen);
+            self.deduped_gens.remove(&gen);
+            self.options.files.remove(&log_path(&self.path, gen))?;
+        }
+        // carried tombstones stay stale
+        let reclaimed = small_size.saturating_sub(compacted_size);
+        self.uncompacted = self.uncompacted.saturating_sub(reclaimed);
+        Ok(reclaimed)
+    }
+
+    // the keys removed in generation `gen` that are still absent
+    fn removed_keys(&mut self, gen: u64) -> Result<BTreeSet<String>> {
+        let mut removed = BTreeSet::new();
+        let reader = self.readers.get_mut(gen)?;
+        read_header(reader)?;
+        for cmd in Deserializer::from_reader(reader).into_iter::<Command>() {
+            match cmd {
+                Ok(Command::Remove { key })
+                    if !self.index_map.contains_key(&*self.options.index_key(&key)) =>
+                {
+                    removed.insert(key);
+                }
+                Ok(_) => {}
+                Err(e) if e.is_eof() => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(removed)
+    }
+
+    // move the live entries of generation `gen` into the active one and delete its file,
+    // retiring a single log without compacting the others
+    // its tombstones are carried along while an older generation may still hold the
+    // removed keys; older versions kept in it are dropped
+    // the active generation can't be dropped, `rotate` away from it first
+    pub fn drop_generation(&mut self, gen: u64) -> Result<()> {
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        if self.compacting {
+            return Err(KvsError::CompactionInProgress);
+        }
+        if gen == self.current_gen {
+            return Err(KvsError::DropActiveGeneration);
+        }
+        if !self.readers.gens().contains(&gen) {
+            return Err(KvsError::GenerationNotFound(gen));
+        }
+        let size = self.options.files.len(&log_path(&self.path, gen))?;
+        let live = self
+            .index_map
+            .iter()
+            .filter(|(_, cmd_pos)| cmd_pos.gen == gen)
+            .map(|(key, cmd_pos)| (key.clone(), *cmd_pos))
+            .collect::<Vec<_>>();
+        let removed = if self.readers.gens().range(..gen).next().is_some() {
+            self.removed_keys(gen)?
+        } else {
+            BTreeSet::new()
+        };
+
+        let active_gen = self.current_gen;
+        let mut throttle = Throttle::new(self.options.compaction_rate_limit);
+        let (moved, tombstones) = self.write_atomic(|store| {
+            store.writer()?;
+            let writer = store.writer.as_mut().expect("log writer not created");
+            let mut moved = Vec::with_capacity(live.len());
+            for (_, cmd_pos) in &live {
+                moved.push(copy_entry(
+                    &mut store.readers,
+                    &store.deduped_gens,
+                    writer,
+                    active_gen,
+                    cmd_pos,
+                    None,
+                    &mut throttle,
+                )?);
+            }
+            let mut tombstones = 0;
+            for key in removed {
+                let range = writer.write_record(&Command::remove(key))?;
+                tombstones += range.end - range.start;
+            }
+            Ok((moved, tombstones))
+        })?;
+        self.bytes_written += moved.iter().map(|cmd_pos| cmd_pos.len).sum::<u64>() + tombstones;
+
+        let mut live_len = 0;
+        for ((key, old_pos), new_pos) in live.into_iter().zip(moved) {
+            live_len += old_pos.len;
+            *self.index_map.get_mut(&key).expect("moved entry not found") = new_pos;
+        }
+        for older in self.versions.older.values_mut() {
+            older.retain(|old_pos| {
+                if old_pos.gen == gen {
+                    live_len += old_pos.len;
+                }
+                old_pos.gen != gen
+            });
+        }
+        self.readers.remove(gen);
+        self.deduped_gens.remove(&gen);
+        self.options.files.remove(&log_path(&self.path, gen))?;
+        // the stale records went with the file, the carried tombstones stay stale
+        self.uncompacted = self
+            .uncompacted
+            .saturating_sub(size.saturating_sub(live_len))
+            + tombstones;
+        info!("dropped log generation {}", gen);
+        Ok(())
+    }
+
+    // close the current log and start a new generation without compacting
+    // returns the generation that subsequent writes go to
+    pub fn rotate(&mut self) -> Result<u64> {
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        if let Some(writer) = &mut self.writer {
+            writer.flush()?;
+        }
+        self.switch_writer(self.next_gen(1)?)?;
+        debug!("rotated to log generation {}", self.current_gen);
+        Ok(self.current_gen)
+    }
+
+    // close the store, reporting errors a plain drop would swallow
+    // compacts first if `compact_on_close` is set
+    pub fn close(mut self) -> Result<()> {
+        let res = self.close_inner();
+        // don't repeat the work in `drop`
+        self.options.compact_on_close = false;
+        res
+    }
+
+    fn close_inner(&mut self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        if self.options.compact_on_close && self.uncompacted > 0 {
+            self.compact()?;
+        }
+        if let Some(writer) = &mut self.writer {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    // number of live keys
+    pub fn len(&self) -> usize {
+        self.index_map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index_map.is_empty()
+    }
+
+    // rough estimate of the memory held by the store, broken down by component
+    // map entries are counted as key bytes plus the stored value, without the
+    // overhead of the map nodes, so the numbers are a lower bound
+    // the store keeps no values in memory, they are always read from the logs
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let key_size = |key: &str| (key.len() + mem::size_of::<Box<str>>()) as u64;
+        let index = self
+            .index_map
+            .keys()
+            .map(|key| key_size(key) + mem::size_of::<CommandPos>() as u64)
+            .sum();
+        let versions = self
+            .versions
+            .older
+            .iter()
+            .map(|(key, older)| {
+                key_size(key)
+                    + (mem::size_of::<VecDeque<CommandPos>>()
+                        + older.capacity() * mem::size_of::<CommandPos>())
+                        as u64
+            })
+            .sum();
+        let modified = self
+            .modified
+            .keys()
+            .map(|key| key_size(key) + mem::size_of::<SystemTime>() as u64)
+            .sum();
+        MemoryUsage {
+            index,
+            versions,
+            modified,
+            readers: self.readers.memory_usage(),
+            writer: self
+                .writer
+                .as_ref()
+                .map_or(0, |_| (mem::size_of::<LogWriter>() + IO_BUFFER_SIZE) as u64),
+        }
+    }
+
+    // remove every key by deleting all log files and starting over with a fresh generation
+    // much cheaper than removing the keys one by one, but a crash in the middle may
+    // leave some of the old entries behind
+    pub fn clear(&mut self) -> Result<()> {
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        if self.compacting {
+            return Err(KvsError::CompactionInProgress);
+        }
+        let gen = self.next_gen(1)?;
+        self.switch_writer(gen)?;
+        self.save_last_version()?;
+        let old_gens = self
+            .readers
+            .gens()
+            .range(..gen)
+            .cloned()
+            .collect::<Vec<_>>();
+        for old_gen in old_gens {
+            self.readers.remove(old_gen);
+            self.options.files.remove(&log_path(&self.path, old_gen))?;
+        }
+        self.index_map.clear();
+        self.versions.older.clear();
+        self.deduped_gens.clear();
+        self.modified.clear();
+        self.secondary.clear();
+        self.uncompacted = 0;
+        self.ops_since_compaction = 0;
+        info!("cleared {}", self.path.display());
+        Ok(())
+    }
+
+    // list and replay the logs again after another process changed the directory,
+    // e.g. to recover from a compaction by a second handle on the same store
+    // every reader is reopened and writes continue in a new generation; older
+    // versions kept by `KvStoreOptions::keep_versions` are replayed from the logs too
+    pub fn refresh(&mut self) -> Result<()> {
+        if self.compacting {
+            return Err(KvsError::CompactionInProgress);
+        }
+        if let Some(writer) = self.writer.as_mut() {
+            writer.flush()?;
+        }
+        let gen_list = sorted_generation_list(&*self.options.files, &self.path)?;
+        let mut readers = ReaderPool::new(
+            self.path.clone(),
+            self.options.files.clone(),
+            self.options.max_open_readers,
+        );
+        let mut replay = Replay::new(self.options.keep_versions);
+        replay_logs(
+            &self.path,
+            &self.options,
+            &gen_list,
+            &mut replay,
+            &mut readers,
+        )?;
+        info!(
+            "refreshed {} with {} keys from {} generations",
+            self.path.display(),
+            replay.index_map.len(),
+            gen_list.len()
+        );
+        let last_gen = gen_list.last().cloned().unwrap_or(0).max(self.current_gen);
+        readers.hits = self.readers.hits;
+        readers.misses = self.readers.misses;
+        self.readers = readers;
+        self.index_map = replay.index_map;
+        self.versions = replay.versions;
+        self.deduped_gens = replay.deduped_gens;
+        self.uncompacted = replay.uncompacted;
+        self.last_version = self.last_version.max(replay.last_version);
+        self.modified = replay.modified;
+        self.rebuild_secondary_indexes()?;
+        self.writer = None;
+        self.current_gen = last_gen;
+        if !self.read_only {
+            let gen = self.next_gen(1)?;
+            self.switch_writer(gen)?;
+        }
+        Ok(())
+    }
+
+    // the keys last set at `start <= time < end`, in order
+    // only keys set with `KvStoreOptions::track_modified` enabled have a time
+    pub fn keys_modified_between(&self, start: SystemTime, end: SystemTime) -> Vec<String> {
+        self.modified
+            .iter()
+            .filter(|(_, &time)| start <= time && time < end)
+            .map(|(key, _)| key.to_string())
+            .collect()
+    }
+
+    // bytes taken by the live entries in the logs, excluding stale records
+    pub fn live_bytes(&self) -> u64 {
+        self.index_map.values().map(|cmd_pos| cmd_pos.len).sum()
+    }
+
+    // bytes appended to the logs, including compaction rewrites, per byte of key and
+    // value set since the store was opened, 0 before anything was set
+    pub fn write_amplification(&self) -> f64 {
+        if self.logical_bytes_written == 0 {
+            return 0.0;
+        }
+        self.bytes_written as f64 / self.logical_bytes_written as f64
+    }
+
+    // render the store's metrics in the prometheus text exposition format
+    pub fn prometheus_metrics(&self) -> String {
+        let lookups = self.readers.hits + self.readers.misses;
+        let hit_ratio = if lookups == 0 {
+            0.0
+        } else {
+            self.readers.hits as f64 / lookups as f64
+        };
+        let metrics = [
+            (
+                "kvs_live_keys",
+                "Number of live keys in the index.",
+                "gauge",
+                self.index_map.len().to_string(),
+            ),
+            (
+                "kvs_uncompacted_bytes",
+                "Stale bytes a compaction would reclaim.",
+                "gauge",
+                self.uncompacted.to_string(),
+            ),
+            (
+                "kvs_generations",
+                "Number of log generations in use.",
+                "gauge",
+                self.readers.gens().len().to_string(),
+            ),
+            (
+                "kvs_writes_since_compaction",
+                "Writes made since the last compaction.",
+                "gauge",
+                self.ops_since_compaction.to_string(),
+            ),
+            (
+                "kvs_gets_total",
+                "Keys looked up since the store was opened.",
+                "counter",
+                self.ops.gets.to_string(),
+            ),
+            (
+                "kvs_sets_total",
+                "Keys set since the store was opened.",
+                "counter",
+                self.ops.sets.to_string(),
+            ),
+            (
+                "kvs_removes_total",
+                "Keys removed since the store was opened.",
+                "counter",
+                self.ops.removes.to_string(),
+            ),
+            (
+                "kvs_reader_cache_hits_total",
+                "Log reads served by an open reader.",
+                "counter",
+                self.readers.hits.to_string(),
+            ),
+            (
+                "kvs_reader_cache_misses_total",
+                "Log reads that had to open the log file.",
+                "counter",
+                self.readers.misses.to_string(),
+            ),
+            (
+                "kvs_reader_cache_hit_ratio",
+                "Share of log reads served by an open reader, 0 before the first one.",
+                "gauge",
+                hit_ratio.to_string(),
+            ),
+        ];
+        let mut out = String::new();
+        for (name, help, kind, value) in metrics {
+            out.push_str(&format!(
+                "# HELP {} {}\n# TYPE {} {}\n{} {}\n",
+                name, help, name, kind, name, value
+            ));
+        }
+        out
+    }
+
+    // sorted generation numbers of the log files currently in use
+    pub fn generations(&self) -> Vec<u64> {
+        self.readers.gens().iter().cloned().collect()
+    }
+
+    // run `write` against the active log and flush it, all or nothing
+    // on failure the log is cut back to where it was, so no partial record
+    // is left behind for `load` to trip over; if that fails as well, writes
+    // continue in a new generation
+    fn write_atomic<T>(&mut self, write: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        let start = self.writer()?.pos;
+        let res = write(self).and_then(|res| {
+            let sync = self.options.sync_policy == SyncPolicy::EveryWrite;
+            let writer = self.writer()?;
+            writer.flush()?;
+            if sync {
+                writer.sync()?;
             }
+            Ok(res)
+        });
+        if res.is_err() {
+            if let Some(writer) = self.writer.take() {
+                match writer.truncate(start) {
+                    Ok(writer) => self.writer = Some(writer),
+                    Err(e) => {
+                        warn!(
+                            "failed to truncate log generation {} to {}: {}",
+                            self.current_gen, start, e
+                        );
+                        // appending to it again would start behind the bytes left there
+                        // with positions counted from 0; `load` skips them as a torn tail
+                        if let Ok(gen) = self.next_gen(1) {
+                            self.current_gen = gen;
+                        }
+                    }
+                }
+            }
+        }
+        res
+    }
+
+    // append a command to the current log without flushing it
+    // returns the range it occupies in the log
+    fn write_command(&mut self, cmd: &Command) -> Result<Range<u64>> {
+        let range = self.writer()?.write_record(cmd)?;
+        self.bytes_written += range.end - range.start;
+        Ok(range)
+    }
+
+    // the time to record with a set, see `KvStoreOptions::track_modified`
+    fn modified_now(&self) -> Option<u64> {
+        if !self.options.track_modified {
+            return None;
+        }
+        let since_epoch = self.options.clock.now().duration_since(UNIX_EPOCH);
+        Some(since_epoch.map_or(0, |d| d.as_millis() as u64))
+    }
+
+    // writer of the current log, fails if the store is read-only
+    // creates the log file of the current generation if needed
+    fn writer(&mut self) -> Result<&mut LogWriter> {
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        if self.writer.is_none() {
+            self.writer = Some(self.new_log_file(self.current_gen)?);
+        }
+        Ok(self.writer.as_mut().expect("log writer not created"))
+    }
+
+    // the generation `n` after the current one
+    // fails instead of wrapping around to generations that may still be in use
+    fn next_gen(&self, n: u64) -> Result<u64> {
+        self.current_gen
+            .checked_add(n)
+            .ok_or(KvsError::GenerationOverflow)
+    }
+
+    // make `gen` the generation new entries are written to
+    fn switch_writer(&mut self, gen: u64) -> Result<()> {
+        self.current_gen = gen;
+        self.writer = None;
+        if self.options.create_log_on_open {
+            self.writer = Some(self.new_log_file(gen)?);
+        }
+        Ok(())
+    }
+
+    // compaction triggered inline by `set`/`remove`
+    // called once after every write, which also counts it towards `compact_every_n_ops`
+    fn compact_if_needed(&mut self) -> Result<()> {
+        self.ops_since_compaction += 1;
+        self.last_write = self.options.clock.now();
+        // a shared compaction takes care of it once it finishes
+        if self.should_compact() && !self.compacting {
+            self.compact()?;
+            if self.should_compact() {
+                self.compact_with(CompactionStrategy::Full)?;
+            }
+        }
+        Ok(())
+    }
+
+    // whether `set`/`remove` should trigger an inline compaction
+    fn should_compact(&self) -> bool {
+        let ops_reached = self
+            .options
+            .compact_every_n_ops
+            .is_some_and(|ops| self.ops_since_compaction >= ops);
+        let gens_exceeded = self
+            .options
+            .max_generations
+            .is_some_and(|max| self.readers.gens().len() > max);
+        self.options.auto_compact
+            && (self.uncompacted > self.options.compaction_threshold
+                || ops_reached
+                || gens_exceeded)
+    }
+
+    // where a compaction writes generation `gen` until it is complete
+    fn compaction_path(&self, gen: u64) -> PathBuf {
+        self.options
+            .compaction_dir
+            .as_deref()
+            .unwrap_or(&self.path)
+            .join(format!("{}.compacting", gen))
+    }
+
+    // start the log written by a compaction, see `install_compaction_log`
+    fn compaction_writer(&self, gen: u64) -> Result<LogWriter> {
+        let file = self.options.files.create(&self.compaction_path(gen))?;
+        let mut writer = BufWriterWithPos::new(file)?;
+        writer.write_header(&self.options.log_header())?;
+        writer.retry = self.options.retry;
+        writer.newline_delimited = self.options.newline_delimited;
+        Ok(writer)
+    }
+
+    // move the finished log of a compaction into the store as generation `gen`
+    fn install_compaction_log(&mut self, gen: u64, mut writer: LogWriter) -> Result<()> {
+        self.save_last_version()?;
+        writer.flush()?;
+        if self.options.sync_policy == SyncPolicy::EveryWrite {
+            writer.sync()?;
+        }
+        // only the records count, like for the logs written by the store
+        self.bytes_written += writer.pos - self.options.log_header().len() as u64;
+        drop(writer);
+        let path = log_path(&self.path, gen);
+        self.options
+            .files
+            .rename(&self.compaction_path(gen), &path)?;
+        if self.options.sync_policy == SyncPolicy::EveryWrite {
+            self.options.files.sync_dir(&self.path)?;
+        }
+        self.readers
+            .insert(gen, BufReaderWithPos::new(self.options.files.open(&path)?)?);
+        Ok(())
+    }
+
+    // keep the highest version handed out in the manifest before the logs lose the
+    // records it may only be found in, so that a version is never handed out twice
+    fn save_last_version(&self) -> Result<()> {
+        if self.last_version == 0 {
+            return Ok(());
+        }
+        let files = &*self.options.files;
+        let mut manifest =
+            Manifest::read(files, &self.path)?.unwrap_or_else(|| Manifest::new(&self.options));
+        if manifest.last_version < self.last_version {
+            manifest.last_version = self.last_version;
+            manifest.write(files, &self.path)?;
+            if self.options.sync_policy == SyncPolicy::EveryWrite {
+                files.sync_dir(&self.path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn new_log_file(&mut self, gen: u64) -> Result<LogWriter> {
+        // the manifest is written along with the first log, so an untouched directory stays empty
+        if !self.options.files.exists(&self.path.join(MANIFEST)) {
+            Manifest::new(&self.options).write(&*self.options.files, &self.path)?;
+        }
+        let mut writer = new_log_file(&*self.options.files, &self.path, gen, &mut self.readers)?;
+        writer.write_header(&self.options.log_header())?;
+        // the new file, and the manifest, must still be there to find the synced writes
+        if self.options.sync_policy == SyncPolicy::EveryWrite {
+            self.options.files.sync_dir(&self.path)?;
+        }
+        writer.retry = self.options.retry;
+        writer.newline_delimited = self.options.newline_delimited;
+        Ok(writer)
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        KvStore::set(self, key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        KvStore::get(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        KvStore::remove(self, key)
+    }
+
+    fn contains_keys(&self, keys: &[String]) -> Vec<bool> {
+        KvStore::contains_keys(self, keys)
+    }
+
+    fn scan_pattern(&mut self, pattern: &str, limit: Option<usize>) -> Result<Vec<String>> {
+        KvStore::scan_pattern(self, pattern, limit)
+    }
+
+    fn range(
+        &mut self,
+        start: &str,
+        end: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        KvStore::range(self, start, end, limit)
+    }
+}
+
+impl Drop for KvStore {
+    fn drop(&mut self) {
+        if self.options.compact_on_close {
+            if let Err(e) = self.close_inner() {
+                warn!("failed to compact {} on drop: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+// copy the entry at `cmd_pos` to the end of `writer` for generation `gen`
+// returns the position of the copy
+// with `blobs`, the value is written as a blob unless one holds it already and the
+// entry becomes a `SetRef`; entries of `deduped_gens` are resolved into plain sets
+// otherwise, as their blobs stay behind
+fn copy_entry(
+    readers: &mut ReaderPool,
+    deduped_gens: &BTreeSet<u64>,
+    writer: &mut LogWriter,
+    gen: u64,
+    cmd_pos: &CommandPos,
+    blobs: Option<&mut HashMap<String, Range<u64>>>,
+    throttle: &mut Throttle,
+) -> Result<CommandPos> {
+    let reader = readers.get_mut(cmd_pos.gen)?;
+    let start = writer.pos;
+    if blobs.is_none() && !deduped_gens.contains(&cmd_pos.gen) {
+        if reader.pos != cmd_pos.pos {
+            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        }
+        let mut entry_reader = reader.take(cmd_pos.len);
+        let len = io::copy(&mut entry_reader, writer)?;
+        throttle.consume(len);
+        return Ok((gen, start..start + len).into());
+    }
+
+    let SetEntry {
+        key,
+        value,
+        version,
+        modified,
+    } = read_set(reader, cmd_pos)?;
+    let cmd = match blobs {
+        // a versioned value keeps a record of its own, as a `SetRef` has no version
+        Some(blobs) if version.is_none() => {
+            let blob = match blobs.get(&value) {
+                Some(blob) => blob.clone(),
+                None => {
+                    let blob = writer.write_record(&Command::Blob {
+                        value: value.clone(),
+                    })?;
+                    blobs.insert(value, blob.clone());
+                    blob
+                }
+            };
+            Command::SetRef {
+                key,
+                pos: blob.start,
+                len: blob.end - blob.start,
+                modified,
+            }
+        }
+        _ => Command::Set {
+            key,
+            value,
+            version,
+            modified,
+        },
+    };
+    let entry = writer.write_record(&cmd)?;
+    throttle.consume(writer.pos - start);
+    Ok((gen, entry).into())
+}
+
+// keep the time `key` was set at, in milliseconds since the unix epoch, if it has one
+fn record_modified(modified: &mut BTreeMap<Box<str>, SystemTime>, key: &str, millis: Option<u64>) {
+    match millis {
+        Some(millis) => {
+            modified.insert(key.into(), UNIX_EPOCH + Duration::from_millis(millis));
+        }
+        None => {
+            modified.remove(key);
+        }
+    }
+}
+
+// point `key` at its newest entry and keep the replaced one as an older version
+// returns the number of bytes that became stale
+fn insert_version(
+    index_map: &mut BTreeMap<Box<str>, CommandPos>,
+    versions: &mut Versions,
+    key: String,
+    cmd_pos: CommandPos,
+) -> u64 {
+    match index_map.get_mut(key.as_str()) {
+        Some(current) => versions.retire(&key, mem::replace(current, cmd_pos)),
+        None => {
+            index_map.insert(key.into(), cmd_pos);
+            0
+        }
+    }
+}
+
+// check the header of a log file and move `reader` to its first record,
+// returns the offset of that record and the flags of the header
+// files written before the header was introduced start right with a record,
+// and a header cut short by a crash leaves a file without any records; neither
+// has flags
+fn read_header<R: Read + Seek>(reader: &mut R) -> Result<(u64, Option<u16>)> {
+    reader.seek(SeekFrom::Start(0))?;
+    let mut stream = Deserializer::from_reader(&mut *reader).into_iter::<LogHeader>();
+    let (start, flags) = match stream.next() {
+        Some(Ok(header)) => {
+            // flags this version doesn't know may change how the records are read
+            if header.kvs_log != LOG_VERSION || header.flags & !KNOWN_FLAGS != 0 {
+                return Err(KvsError::UnsupportedFormat {
+                    version: header.kvs_log,
+                });
+            }
+            (stream.byte_offset() as u64, Some(header.flags))
+        }
+        // an object that isn't a header is the first record of a file without one,
+        // and after a header cut short the records are read as a torn tail
+        Some(Err(e)) if e.is_data() || e.is_eof() => (0, None),
+        Some(Err(e)) if e.is_io() => return Err(e.into()),
+        Some(Err(_)) => return Err(KvsError::UnsupportedFormat { version: 0 }),
+        None => (0, None),
+    };
+    reader.seek(SeekFrom::Start(start))?;
+    Ok((start, flags))
+}
+
+#[cfg(all(feature = "direct-io", target_os = "linux"))]
+fn direct_file_provider(dir: &Path) -> Result<Arc<dyn FileProvider>> {
+    Ok(Arc::new(direct::DirectFileProvider::new(dir)?))
+}
+
+#[cfg(not(all(feature = "direct-io", target_os = "linux")))]
+fn direct_file_provider(_dir: &Path) -> Result<Arc<dyn FileProvider>> {
+    Err(KvsError::DirectIoUnsupported)
+}
+
+fn new_log_file(
+    files: &dyn FileProvider,
+    path: &Path,
+    gen: u64,
+    readers: &mut ReaderPool,
+) -> Result<LogWriter> {
+    let path = log_path(path, gen);
+    debug!("starting log generation {}", gen);
+    let writer = BufWriterWithPos::new(files.append(&path)?)?;
+    readers.insert(gen, BufReaderWithPos::new(files.open(&path)?)?);
+    Ok(writer)
+}
+
+// walk every command recorded in the store at `path`, oldest generation first
+// unlike the live view this includes overwritten entries and tombstones
+// values are yielded as stored, so they stay encrypted if encryption is enabled
+pub fn scan_raw(path: &Path) -> Result<impl Iterator<Item = Result<(u64, Command)>>> {
+    scan_raw_with_options(path, KvStoreOptions::default())
+}
+
+// like `scan_raw`, reading through the file provider of `options`
+pub fn scan_raw_with_options(
+    path: &Path,
+    options: KvStoreOptions,
+) -> Result<impl Iterator<Item = Result<(u64, Command)>>> {
+    let dir = path.to_owned();
+    let gens = sorted_generation_list(&*options.files, path)?;
+    Ok(gens.into_iter().flat_map(
+        move |gen| -> Box<dyn Iterator<Item = Result<(u64, Command)>>> {
+            match GenerationReader::open_with_options(&dir, gen, options.clone()) {
+                Ok(reader) => Box::new(reader.map(|res| res.map(|(pos, cmd)| (pos.gen, cmd)))),
+                Err(e) => Box::new(iter::once(Err(e))),
+            }
+        },
+    ))
+}
+
+// the commands of a single generation file with their positions, read one at a time
+// the framing is the same as when a store loads the file, so a torn record at the
+// end is skipped rather than reported
+pub struct GenerationReader {
+    gen: u64,
+    stream: StreamDeserializer<'static, IoRead<LogReader>, Command>,
+    // offset of the first record, after the header
+    start: u64,
+    pos: u64,
+    done: bool,
+}
+
+impl GenerationReader {
+    // open generation `gen` of the store in `dir`
+    pub fn open(dir: &Path, gen: u64) -> Result<Self> {
+        Self::open_with_options(dir, gen, KvStoreOptions::default())
+    }
+
+    // like `open`, reading through the file provider of `options`
+    pub fn open_with_options(dir: &Path, gen: u64, options: KvStoreOptions) -> Result<Self> {
+        let mut reader = BufReaderWithPos::new(options.files.open(&log_path(dir, gen))?)?;
+        let (start, _) = read_header(&mut reader)?;
+        Ok(Self {
+            gen,
+            stream: Deserializer::from_reader(reader).into_iter(),
+            start,
+            pos: start,
+            done: false,
+        })
+    }
+
+    pub fn gen(&self) -> u64 {
+        self.gen
+    }
+}
 
-            let mut entry_reader = reader.take(cmd_pos.len);
-            let len = io::copy(&mut entry_reader, &mut writer)?;
-            *cmd_pos = (compaction_gen, new_pos..new_pos + len).into();
-            new_pos += len;
-        }
+impl Iterator for GenerationReader {
+    type Item = Result<(CommandPos, Command)>;
 
-        writer.flush()?;
-        let stales_gens = self
-            .readers
-            .keys()
-            .filter(|&&k| k < compaction_gen)
-            .cloned()
-            .collect::<Vec<_>>();
-        for gen in stales_gens {
-            self.readers.remove(&gen);
-            fs::remove_file(log_path(&self.path, gen))?;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
         }
-        self.uncompacted = 0;
-        Ok(())
+        let res = match self.stream.next()? {
+            Ok(cmd) => {
+                let new_pos = self.start + self.stream.byte_offset() as u64;
+                let cmd_pos = CommandPos::from((self.gen, self.pos..new_pos));
+                self.pos = new_pos;
+                Ok((cmd_pos, cmd))
+            }
+            Err(e) if e.is_eof() => {
+                warn!("gen {}: ignoring torn tail at {}", self.gen, self.pos);
+                self.done = true;
+                return None;
+            }
+            Err(e) => {
+                self.done = true;
+                Err(e.into())
+            }
+        };
+        Some(res)
     }
+}
 
-    fn new_log_file(&mut self, gen: u64) -> Result<BufWriterWithPos<File>> {
-        new_log_file(&self.path, gen, &mut self.readers)
+// copy and replay the generations of `backup` into `dest` one by one, then its manifest
+// `restored` collects the generations copied so far
+fn restore_generations(
+    backup: &Path,
+    dest: &Path,
+    options: &KvStoreOptions,
+    restored: &mut Vec<u64>,
+) -> Result<()> {
+    let mut replay = Replay::new(1);
+    let files = &*options.files;
+    for gen in sorted_generation_list(files, backup)? {
+        let path = log_path(dest, gen);
+        restored.push(gen);
+        files.write(&path, &files.read(&log_path(backup, gen))?)?;
+        let mut reader = BufReaderWithPos::new(files.open(&path)?)?;
+        let mut progress = ReplayProgress::default();
+        load(gen, &mut reader, &mut replay, options, &mut progress).map_err(|e| {
+            KvsError::InvalidLog {
+                gen,
+                message: e.to_string(),
+            }
+        })?;
     }
+    // the settings the logs were written with, e.g. whether keys are case-insensitive
+    match files.read(&backup.join(MANIFEST)) {
+        Ok(manifest) => files.write(&dest.join(MANIFEST), &manifest)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    info!(
+        "restored {} keys from {} into {}",
+        replay.index_map.len(),
+        backup.display(),
+        dest.display()
+    );
+    Ok(())
 }
 
-fn new_log_file(
-    path: &Path,
-    gen: u64,
-    readers: &mut HashMap<u64, BufReaderWithPos<File>>,
-) -> Result<BufWriterWithPos<File>> {
-    let path = log_path(path, gen);
-    let writer = BufWriterWithPos::new(OpenOptions::new().create(true).append(true).open(&path)?)?;
-    readers.insert(gen, BufReaderWithPos::new(File::open(path)?)?);
-    Ok(writer)
+// whether `text` matches the glob `pattern` with `*` and `?` wildcards
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // position of the last `*` and of the text it currently matches up to
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                // let the last `*` swallow one more character and retry
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
 }
 
 fn log_path(dir: &Path, gen: u64) -> PathBuf {
     dir.join(format!("{}.log", gen))
 }
 
-fn sorted_generation_list(path: &Path) -> Result<Vec<u64>> {
-    let mut generation_list = fs::read_dir(path)?
-        .flat_map(|s| -> Result<_> { Ok(s?.path()) })
-        .filter(|p| p.is_file() && p.extension() == Some("log".as_ref()))
+fn sorted_generation_list(files: &dyn FileProvider, path: &Path) -> Result<Vec<u64>> {
+    let mut generation_list = files
+        .list(path)?
+        .into_iter()
+        .filter(|p| p.extension() == Some("log".as_ref()))
         .flat_map(|p| {
             p.file_name()
                 .and_then(OsStr::to_str)
@@ -198,41 +2990,325 @@ fn sorted_generation_list(path: &Path) -> Result<Vec<u64>> {
         .flatten()
         .collect::<Vec<_>>();
     generation_list.sort_unstable();
+    // e.g. `5.log` next to `05.log`, it's unclear which one holds the data
+    if let Some(pair) = generation_list.windows(2).find(|pair| pair[0] == pair[1]) {
+        return Err(KvsError::DuplicateGeneration { gen: pair[0] });
+    }
     Ok(generation_list)
 }
 
+// what replaying the logs rebuilds, see `load`
+struct Replay {
+    index_map: BTreeMap<Box<str>, CommandPos>,
+    versions: Versions,
+    deduped_gens: BTreeSet<u64>,
+    uncompacted: u64,
+    last_version: u64,
+    modified: BTreeMap<Box<str>, SystemTime>,
+}
+
+impl Replay {
+    fn new(keep_versions: usize) -> Self {
+        Self {
+            index_map: BTreeMap::new(),
+            versions: Versions::new(keep_versions),
+            deduped_gens: BTreeSet::new(),
+            uncompacted: 0,
+            last_version: 0,
+            modified: BTreeMap::new(),
+        }
+    }
+
+    // point `key` at the set at `cmd_pos`, written at `modified`
+    fn set(&mut self, key: String, cmd_pos: CommandPos, modified: Option<u64>) {
+        record_modified(&mut self.modified, &key, modified);
+        self.uncompacted += insert_version(&mut self.index_map, &mut self.versions, key, cmd_pos);
+    }
+}
+
+// replay the generations `gen_list` of the store in `path` into `replay`
+// and add their readers to `readers`
+fn replay_logs(
+    path: &Path,
+    options: &KvStoreOptions,
+    gen_list: &[u64],
+    replay: &mut Replay,
+    readers: &mut ReaderPool,
+) -> Result<()> {
+    let mut progress = ReplayProgress::default();
+    for &gen in gen_list {
+        let mut reader = BufReaderWithPos::new(options.files.open(&log_path(path, gen))?)?;
+        let flags = load(gen, &mut reader, replay, options, &mut progress)?;
+        // either record layout reads the same, but values need the right key
+        if flags.is_some_and(|flags| (flags & FLAG_ENCRYPTED != 0) != options.encrypted()) {
+            return Err(KvsError::ConfigMismatch {
+                setting: "encryption".to_owned(),
+            });
+        }
+        options.replay_progress(progress);
+        readers.insert(gen, reader);
+    }
+    Ok(())
+}
+
+// key, position and modification time of a set replayed as part of a batch
+type BatchedSet = (String, CommandPos, Option<u64>);
+
+// returns the flags of the log's header, `None` for a log without one
 fn load(
     gen: u64,
-    reader: &mut BufReaderWithPos<File>,
-    index_map: &mut BTreeMap<String, CommandPos>,
-) -> Result<u64> {
-    let mut uncompacted = 0;
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut s = Deserializer::from_reader(reader).into_iter::<Command>();
+    reader: &mut LogReader,
+    replay: &mut Replay,
+    options: &KvStoreOptions,
+    progress: &mut ReplayProgress,
+) -> Result<Option<u16>> {
+    progress.gen = gen;
+    // sets of an unfinished batch with the number it announced
+    let mut batch: Option<(usize, Vec<BatchedSet>)> = None;
+    let (start, flags) = read_header(reader)?;
+    let mut pos = start;
+    progress.bytes += start;
+    let mut s = Deserializer::from_reader(&mut *reader).into_iter::<Command>();
     while let Some(cmd) = s.next() {
-        let new_pos = s.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, .. } => {
-                if let Some(old_cmd) = index_map.insert(key, (gen, (pos..new_pos)).into()) {
-                    uncompacted += old_cmd.len;
+        let new_pos = start + s.byte_offset() as u64;
+        let cmd = match cmd {
+            Ok(cmd) => cmd,
+            // a record cut short by a crash, nothing valid can follow it
+            Err(e) if e.is_eof() => break,
+            Err(e) => return Err(e.into()),
+        };
+        let cmd_pos = CommandPos::from((gen, pos..new_pos));
+        pos = new_pos;
+        progress.records += 1;
+        progress.bytes += cmd_pos.len;
+        if let Command::Set {
+            version: Some(version),
+            ..
+        } = cmd
+        {
+            replay.last_version = replay.last_version.max(version);
+        }
+        if let Some((every, f)) = &options.on_replay_progress {
+            if progress.records.is_multiple_of(*every) {
+                f(*progress);
+            }
+        }
+        match (cmd, batch.as_mut()) {
+            (Command::Set { key, modified, .. }, Some((len, sets))) if sets.len() < *len => {
+                sets.push((options.fold_key(key), cmd_pos, modified));
+            }
+            (Command::BatchEnd, Some((len, sets))) if sets.len() == *len => {
+                trace!("gen {}: batch of {} at {}", gen, len, cmd_pos.pos);
+                for (key, set_pos, modified) in sets.drain(..) {
+                    if options.loads_key(&key) {
+                        replay.set(key, set_pos, modified);
+                    }
                 }
+                batch = None;
+                replay.uncompacted += cmd_pos.len;
             }
-            Command::Remove { key, .. } => {
-                if let Some(old_cmd) = index_map.remove(&key) {
-                    uncompacted += old_cmd.len;
+            (cmd, _) => {
+                // anything else interrupts an unfinished batch, which is dropped
+                if let Some((_, sets)) = batch.take() {
+                    replay.uncompacted +=
+                        sets.iter().map(|(_, set_pos, _)| set_pos.len).sum::<u64>();
+                }
+                match cmd {
+                    Command::Set { key, modified, .. } => {
+                        trace!("gen {}: set {} at {}", gen, key, cmd_pos.pos);
+                        let key = options.fold_key(key);
+                        if options.loads_key(&key) {
+                            replay.set(key, cmd_pos, modified);
+                        }
+                    }
+                    // blobs only live as long as their generation, so they are never stale
+                    Command::Blob { .. } => {
+                        replay.deduped_gens.insert(gen);
+                    }
+                    Command::SetRef { key, modified, .. } => {
+                        trace!("gen {}: set {} by reference at {}", gen, key, cmd_pos.pos);
+                        let key = options.fold_key(key);
+                        if options.loads_key(&key) {
+                            replay.set(key, cmd_pos, modified);
+                        }
+                    }
+                    Command::Remove { key, .. } => {
+                        trace!("gen {}: remove {} at {}", gen, key, cmd_pos.pos);
+                        let key = options.fold_key(key);
+                        if let Some(old_cmd) = replay.index_map.remove(key.as_str()) {
+                            replay.uncompacted += old_cmd.len + replay.versions.remove(&key);
+                        }
+                        replay.modified.remove(key.as_str());
+                        replay.uncompacted += cmd_pos.len;
+                    }
+                    Command::BatchStart { len } => {
+                        // `len` comes from the file, a corrupt one mustn't allocate it all
+                        batch = Some((len, Vec::with_capacity(len.min(1024))));
+                        replay.uncompacted += cmd_pos.len;
+                    }
+                    Command::BatchEnd => replay.uncompacted += cmd_pos.len,
                 }
-                uncompacted += new_pos - pos;
             }
         }
-        pos = new_pos;
     }
-    Ok(uncompacted)
+    if let Some((_, sets)) = batch {
+        warn!(
+            "gen {}: dropping unfinished batch of {} sets",
+            gen,
+            sets.len()
+        );
+        replay.uncompacted += sets.iter().map(|(_, set_pos, _)| set_pos.len).sum::<u64>();
+    }
+    let end = reader.seek(SeekFrom::End(0))?;
+    if end > pos {
+        warn!("gen {}: ignoring {} bytes of torn tail", gen, end - pos);
+        replay.uncompacted += end - pos;
+    }
+    Ok(flags)
 }
 
-struct CommandPos {
-    gen: u64,
-    pos: u64,
-    len: u64,
+// a repeatable view of a store, see `KvStore::read_tx`
+// writes and compactions of the store after its creation are not visible through it
+pub struct ReadTxn {
+    index_map: BTreeMap<Box<str>, CommandPos>,
+    // opened at creation, so the files stay readable even if a compaction removes them
+    readers: HashMap<u64, LogReader>,
+    options: KvStoreOptions,
+}
+
+impl ReadTxn {
+    // get the value the given key had when the transaction started
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        let key = self.options.index_key(key);
+        if let Some(cmd_pos) = self.index_map.get(&*key) {
+            let reader = self
+                .readers
+                .get_mut(&cmd_pos.gen)
+                .expect("cannot find log reader");
+            let value = read_value(reader, cmd_pos)?;
+            Ok(Some(self.options.decode_value(&key, value)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+// whether `e` comes from a record that can't be decoded rather than from io
+fn is_decode_error(e: &KvsError) -> bool {
+    match e {
+        KvsError::SerdeError(e) => !e.is_io(),
+        KvsError::UnexpectedCommandType | KvsError::DecryptionFailed => true,
+        _ => false,
+    }
+}
+
+// read the value of the set command at `cmd_pos`
+fn read_value(reader: &mut LogReader, cmd_pos: &CommandPos) -> Result<String> {
+    read_entry(reader, cmd_pos).map(|(_, value)| value)
+}
+
+// read the key as it was set and the value of the entry at `cmd_pos`
+fn read_entry(reader: &mut LogReader, cmd_pos: &CommandPos) -> Result<(String, String)> {
+    read_set(reader, cmd_pos).map(|set| (set.key, set.value))
+}
+
+// a set entry as read back from the log
+struct SetEntry {
+    key: String,
+    value: String,
+    version: Option<u64>,
+    modified: Option<u64>,
+}
+
+// read the entry at `cmd_pos` with all it carries
+// the value of a `SetRef` is read from its blob
+fn read_set(reader: &mut LogReader, cmd_pos: &CommandPos) -> Result<SetEntry> {
+    match read_command(reader, cmd_pos.pos, cmd_pos.len)? {
+        Command::Set {
+            key,
+            value,
+            version,
+            modified,
+        } => Ok(SetEntry {
+            key,
+            value,
+            version,
+            modified,
+        }),
+        Command::SetRef {
+            key,
+            pos,
+            len,
+            modified,
+        } => Ok(SetEntry {
+            key,
+            value: read_blob(reader, pos, len)?,
+            version: None,
+            modified,
+        }),
+        _ => Err(KvsError::UnexpectedCommandType),
+    }
+}
+
+// read the value of the `Blob` at `pos`
+fn read_blob(reader: &mut LogReader, pos: u64, len: u64) -> Result<String> {
+    if let Command::Blob { value } = read_command(reader, pos, len)? {
+        Ok(value)
+    } else {
+        Err(KvsError::UnexpectedCommandType)
+    }
+}
+
+fn read_command(reader: &mut LogReader, pos: u64, len: u64) -> Result<Command> {
+    reader.seek(SeekFrom::Start(pos))?;
+    Ok(serde_json::from_reader(reader.take(len))?)
+}
+
+// the in-memory index of a store as written by `KvStore::export_index`
+#[derive(Serialize, Deserialize)]
+struct IndexSnapshot<'a> {
+    // generations of the store at the time of the export
+    gens: Vec<u64>,
+    index: Cow<'a, BTreeMap<Box<str>, CommandPos>>,
+    deduped_gens: BTreeSet<u64>,
+    uncompacted: u64,
+    #[serde(default)]
+    last_version: u64,
+    #[serde(default)]
+    modified: Cow<'a, BTreeMap<Box<str>, SystemTime>>,
+}
+
+impl IndexSnapshot<'_> {
+    // check that the snapshot was taken of the generations `gens` of the store in `dir`
+    fn validate(&self, files: &dyn FileProvider, dir: &Path, gens: &[u64]) -> Result<()> {
+        let invalid = |message: String| Err(KvsError::InvalidIndex { message });
+        if self.gens != gens {
+            return invalid(format!(
+                "index of generations {:?}, found {:?}",
+                self.gens, gens
+            ));
+        }
+        let mut sizes = HashMap::new();
+        for &gen in gens {
+            sizes.insert(gen, files.len(&log_path(dir, gen))?);
+        }
+        for (key, cmd_pos) in self.index.iter() {
+            match sizes.get(&cmd_pos.gen) {
+                Some(&size) if cmd_pos.pos + cmd_pos.len <= size => {}
+                Some(_) => return invalid(format!("entry of {} past the end of its log", key)),
+                None => return invalid(format!("entry of {} in an unknown generation", key)),
+            }
+        }
+        Ok(())
+    }
+}
+
+// where a command is stored: its generation, byte offset and length
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandPos {
+    pub gen: u64,
+    pub pos: u64,
+    pub len: u64,
 }
 
 impl From<(u64, Range<u64>)> for CommandPos {
@@ -245,40 +3321,296 @@ impl From<(u64, Range<u64>)> for CommandPos {
     }
 }
 
+// a compaction by `KvStore::compact_shared`, copying a snapshot of the index
+// into generation `gen` outside of the store's lock
+struct SharedCompaction {
+    gen: u64,
+    entries: Vec<(Box<str>, CommandPos)>,
+    readers: ReaderPool,
+    deduped_gens: BTreeSet<u64>,
+    writer: LogWriter,
+    throttle: Throttle,
+    // size of the generations being compacted
+    old_size: u64,
+    start: SystemTime,
+}
+
+impl SharedCompaction {
+    // copy every entry of the snapshot, returns the positions of the copies
+    fn copy(&mut self) -> Result<Vec<CommandPos>> {
+        let mut moved = Vec::with_capacity(self.entries.len());
+        for (_, cmd_pos) in &self.entries {
+            moved.push(copy_entry(
+                &mut self.readers,
+                &self.deduped_gens,
+                &mut self.writer,
+                self.gen,
+                cmd_pos,
+                None,
+                &mut self.throttle,
+            )?);
+        }
+        self.writer.flush()?;
+        Ok(moved)
+    }
+}
+
+// sleeps as needed to keep a copy below a given rate
+struct Throttle {
+    bytes_per_sec: Option<u64>,
+    start: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    fn new(bytes_per_sec: Option<u64>) -> Self {
+        Self {
+            bytes_per_sec,
+            start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    // account for `len` copied bytes
+    fn consume(&mut self, len: u64) {
+        if let Some(bytes_per_sec) = self.bytes_per_sec {
+            self.bytes += len;
+            let target = Duration::from_secs_f64(self.bytes as f64 / bytes_per_sec as f64);
+            let elapsed = self.start.elapsed();
+            if target > elapsed {
+                thread::sleep(target - elapsed);
+            }
+        }
+    }
+}
+
+// operations served by a store, see `KvStore::prometheus_metrics`
+#[derive(Default)]
+struct OpCounters {
+    gets: u64,
+    sets: u64,
+    removes: u64,
+}
+
+// the replaced entries of every key when more than the latest version is kept
+struct Versions {
+    // number of versions kept per key, including the latest one
+    keep: usize,
+    // older versions of each key, newest first
+    older: HashMap<Box<str>, VecDeque<CommandPos>>,
+}
+
+impl Versions {
+    fn new(keep: usize) -> Self {
+        Self {
+            keep,
+            older: HashMap::new(),
+        }
+    }
+
+    // keep `old` as the previous version of `key`, returns the bytes that became stale
+    fn retire(&mut self, key: &str, old: CommandPos) -> u64 {
+        if self.keep <= 1 {
+            return old.len;
+        }
+        let older = match self.older.get_mut(key) {
+            Some(older) => older,
+            None => self.older.entry(key.into()).or_default(),
+        };
+        older.push_front(old);
+        if older.len() < self.keep {
+            0
+        } else {
+            older.pop_back().map_or(0, |dropped| dropped.len)
+        }
+    }
+
+    // forget the older versions of a removed key, returns their size
+    fn remove(&mut self, key: &str) -> u64 {
+        self.older
+            .remove(key)
+            .map_or(0, |older| older.iter().map(|cmd_pos| cmd_pos.len).sum())
+    }
+
+    // the `n`th version before the latest one, starting at one
+    fn get(&self, key: &str, n: usize) -> Option<&CommandPos> {
+        self.older.get(key)?.get(n.checked_sub(1)?)
+    }
+}
+
+// readers of every generation in use
+// log files are opened on demand and the least recently used ones are closed
+// once more than `max_open` are open
+struct ReaderPool {
+    dir: PathBuf,
+    files: Arc<dyn FileProvider>,
+    gens: BTreeSet<u64>,
+    // open readers with the tick they were last used at
+    open: HashMap<u64, (u64, LogReader)>,
+    max_open: Option<usize>,
+    tick: u64,
+    // lookups that found the reader open, and those that had to open it
+    hits: u64,
+    misses: u64,
+}
+
+impl ReaderPool {
+    fn new(dir: PathBuf, files: Arc<dyn FileProvider>, max_open: Option<usize>) -> Self {
+        Self {
+            dir,
+            files,
+            gens: BTreeSet::new(),
+            open: HashMap::new(),
+            max_open,
+            tick: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn gens(&self) -> &BTreeSet<u64> {
+        &self.gens
+    }
+
+    // estimated bytes of the generation set and the open readers with their buffers
+    fn memory_usage(&self) -> u64 {
+        let reader_size = mem::size_of::<(u64, u64, LogReader)>() + IO_BUFFER_SIZE;
+        (self.gens.len() * mem::size_of::<u64>() + self.open.len() * reader_size) as u64
+    }
+
+    fn insert(&mut self, gen: u64, reader: LogReader) {
+        self.gens.insert(gen);
+        self.open_reader(gen, reader);
+    }
+
+    fn remove(&mut self, gen: u64) {
+        self.gens.remove(&gen);
+        self.open.remove(&gen);
+    }
+
+    fn get_mut(&mut self, gen: u64) -> Result<&mut LogReader> {
+        assert!(self.gens.contains(&gen), "cannot find log reader");
+        if self.open.contains_key(&gen) {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+            let reader = BufReaderWithPos::new(self.files.open(&log_path(&self.dir, gen))?)?;
+            self.open_reader(gen, reader);
+        }
+        self.tick += 1;
+        let (used, reader) = self.open.get_mut(&gen).expect("cannot find log reader");
+        *used = self.tick;
+        Ok(reader)
+    }
+
+    fn open_reader(&mut self, gen: u64, reader: LogReader) {
+        if let Some(max_open) = self.max_open {
+            while self.open.len() >= max_open {
+                let lru = self
+                    .open
+                    .iter()
+                    .min_by_key(|(_, (used, _))| *used)
+                    .map(|(&gen, _)| gen)
+                    .expect("no open reader to close");
+                self.open.remove(&lru);
+            }
+        }
+        self.tick += 1;
+        self.open.insert(gen, (self.tick, reader));
+    }
+}
+
 struct BufWriterWithPos<W: Write + Seek> {
     writer: BufWriter<W>,
     pos: u64,
+    retry: RetryPolicy,
+    // start every record on a new line, see `KvStoreOptions::newline_delimited`
+    newline_delimited: bool,
 }
 
 impl<W: Write + Seek> BufWriterWithPos<W> {
     fn new(mut inner: W) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
         Ok(Self {
             writer: BufWriter::new(inner),
             pos,
+            retry: RetryPolicy::default(),
+            newline_delimited: false,
         })
     }
+
+    // start a new file with `header`, flushed right away so that a truncate after
+    // a failed write never cuts into it
+    fn write_header(&mut self, header: &[u8]) -> Result<()> {
+        if self.pos == 0 {
+            self.write_all(header)?;
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    // append `cmd` as a record, returns its position
+    // the newline in front of a delimited record is part of it, just as any
+    // whitespace before a record is when the log is replayed
+    fn write_record(&mut self, cmd: &Command) -> Result<Range<u64>> {
+        let start = self.pos;
+        if self.newline_delimited {
+            self.write_all(b"\n")?;
+        }
+        serde_json::to_writer(&mut *self, cmd)?;
+        Ok(start..self.pos)
+    }
+}
+
+impl BufWriterWithPos<Box<dyn LogFile>> {
+    // sync the flushed data of the file to the disk
+    fn sync(&mut self) -> Result<()> {
+        let retry = self.retry;
+        let file = self.writer.get_mut();
+        Ok(retry.run(|| file.sync_all())?)
+    }
+
+    // drop the buffered bytes and cut the file back to `pos`
+    fn truncate(self, pos: u64) -> Result<Self> {
+        let (retry, newline_delimited) = (self.retry, self.newline_delimited);
+        let (mut file, _unwritten) = self.writer.into_parts();
+        file.set_len(pos)?;
+        file.seek(SeekFrom::Start(pos))?;
+        let mut writer = Self::new(file)?;
+        writer.retry = retry;
+        writer.newline_delimited = newline_delimited;
+        Ok(writer)
+    }
 }
 
 impl<W: Write + Seek> Write for BufWriterWithPos<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let len = self.writer.write(buf)?;
+        let retry = self.retry;
+        let len = retry.run(|| self.writer.write(buf))?;
         self.pos += len as u64;
         Ok(len)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.writer.flush()
+        let retry = self.retry;
+        retry.run(|| self.writer.flush())
     }
 }
 
 impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        self.pos = self.writer.seek(pos)?;
+        self.pos = retry_interrupted(|| self.writer.seek(pos))?;
         Ok(self.pos)
     }
 }
 
+// capacity of the std `BufReader`/`BufWriter` buffers wrapping the log files
+const IO_BUFFER_SIZE: usize = 8 * 1024;
+
+// log readers and writers of a store, on whatever `FileProvider` gives out
+type LogReader = BufReaderWithPos<Box<dyn LogFile>>;
+type LogWriter = BufWriterWithPos<Box<dyn LogFile>>;
+
 struct BufReaderWithPos<R: Read + Seek> {
     reader: BufReader<R>,
     pos: u64,
@@ -286,7 +3618,7 @@ struct BufReaderWithPos<R: Read + Seek> {
 
 impl<R: Read + Seek> BufReaderWithPos<R> {
     fn new(mut inner: R) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
         Ok(Self {
             reader: BufReader::new(inner),
             pos,
@@ -296,7 +3628,7 @@ impl<R: Read + Seek> BufReaderWithPos<R> {
 
 impl<R: Read + Seek> Read for BufReaderWithPos<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let len = self.reader.read(buf)?;
+        let len = retry_interrupted(|| self.reader.read(buf))?;
         self.pos += len as u64;
         Ok(len)
     }
@@ -304,11 +3636,21 @@ impl<R: Read + Seek> Read for BufReaderWithPos<R> {
 
 impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        self.pos += self.reader.seek(pos)?;
+        self.pos = retry_interrupted(|| self.reader.seek(pos))?;
         Ok(self.pos)
     }
 }
 
+// retry an io operation as long as it fails with `ErrorKind::Interrupted`
+fn retry_interrupted<T>(mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    loop {
+        match op() {
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            res => return res,
+        }
+    }
+}
+
 #[derive(Fail, Debug)]
 pub enum KvsError {
     #[fail(display = "{}", _0)]
@@ -319,18 +3661,377 @@ pub enum KvsError {
     KeyNotFound,
     #[fail(display = "Unexpected command type")]
     UnexpectedCommandType,
+    #[fail(display = "Store is opened read-only")]
+    ReadOnly,
+    #[fail(display = "Failed to decrypt value")]
+    DecryptionFailed,
+    #[fail(display = "Restore target already holds a store")]
+    RestoreTargetNotEmpty,
+    #[fail(display = "Bulk load target already holds a store")]
+    BulkLoadTargetNotEmpty,
+    #[fail(display = "A shared compaction is in progress")]
+    CompactionInProgress,
+    #[fail(display = "No space left on the device")]
+    DiskFull,
+    #[fail(display = "Compaction directory is not on the store's filesystem")]
+    CrossDeviceRename,
+    #[fail(display = "More than one log file for generation {}", gen)]
+    DuplicateGeneration { gen: u64 },
+    #[fail(display = "Invalid log generation {}: {}", gen, message)]
+    InvalidLog { gen: u64, message: String },
+    #[fail(display = "Invalid index: {}", message)]
+    InvalidIndex { message: String },
+    #[fail(display = "Invalid frozen store: {}", message)]
+    InvalidFrozen { message: String },
+    #[fail(display = "No engine registered as {}", _0)]
+    UnknownEngine(String),
+    #[fail(display = "No secondary index named {}", _0)]
+    UnknownSecondaryIndex(String),
+    // a log file of another format version or with flags it doesn't know,
+    // version 0 if it doesn't hold JSON at all
+    #[fail(display = "Unsupported log format version {}", version)]
+    UnsupportedFormat { version: u16 },
+    #[fail(display = "Direct I/O is not supported here")]
+    DirectIoUnsupported,
+    #[fail(display = "The active log generation can't be dropped")]
+    DropActiveGeneration,
+    #[fail(display = "Log generation numbers are exhausted")]
+    GenerationOverflow,
+    #[fail(display = "Log generation {} not found", _0)]
+    GenerationNotFound(u64),
+    #[fail(display = "Option {} differs from the store's manifest", setting)]
+    ConfigMismatch { setting: String },
+}
+
+// variants compare structurally
+// wrapped io errors are equal if they have the same `io::ErrorKind` and
+// wrapped serde errors if they have the same `serde_json::error::Category`,
+// whatever their messages
+impl PartialEq for KvsError {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (KvsError::IOError(a), KvsError::IOError(b)) => a.kind() == b.kind(),
+            (KvsError::SerdeError(a), KvsError::SerdeError(b)) => a.classify() == b.classify(),
+            (
+                KvsError::InvalidLog { gen, message },
+                KvsError::InvalidLog {
+                    gen: other_gen,
+                    message: other_message,
+                },
+            ) => gen == other_gen && message == other_message,
+            (
+                KvsError::InvalidIndex { message },
+                KvsError::InvalidIndex {
+                    message: other_message,
+                },
+            ) => message == other_message,
+            (
+                KvsError::InvalidFrozen { message },
+                KvsError::InvalidFrozen {
+                    message: other_message,
+                },
+            ) => message == other_message,
+            (KvsError::GenerationNotFound(a), KvsError::GenerationNotFound(b)) => a == b,
+            (
+                KvsError::UnsupportedFormat { version },
+                KvsError::UnsupportedFormat {
+                    version: other_version,
+                },
+            ) => version == other_version,
+            (KvsError::UnknownEngine(a), KvsError::UnknownEngine(b)) => a == b,
+            (KvsError::UnknownSecondaryIndex(a), KvsError::UnknownSecondaryIndex(b)) => a == b,
+            (
+                KvsError::ConfigMismatch { setting },
+                KvsError::ConfigMismatch {
+                    setting: other_setting,
+                },
+            ) => setting == other_setting,
+            (
+                KvsError::DuplicateGeneration { gen },
+                KvsError::DuplicateGeneration { gen: other_gen },
+            ) => gen == other_gen,
+            (a, b) => mem::discriminant(a) == mem::discriminant(b),
+        }
+    }
+}
+
+// errno of a full device on unix systems, other systems only report the error kind
+#[cfg(unix)]
+const ENOSPC: i32 = 28;
+
+#[cfg(unix)]
+fn is_disk_full(err: &Error) -> bool {
+    err.kind() == io::ErrorKind::StorageFull || err.raw_os_error() == Some(ENOSPC)
+}
+
+#[cfg(not(unix))]
+fn is_disk_full(err: &Error) -> bool {
+    err.kind() == io::ErrorKind::StorageFull
 }
 
 impl From<io::Error> for KvsError {
     fn from(err: Error) -> Self {
+        if is_disk_full(&err) {
+            return KvsError::DiskFull;
+        }
         KvsError::IOError(err)
     }
 }
 
 impl From<serde_json::Error> for KvsError {
     fn from(err: serde_json::Error) -> Self {
+        // an io error raised while (de)serializing is reported like any other one
+        if err.is_io() {
+            return io::Error::from(err).into();
+        }
         KvsError::SerdeError(err)
     }
 }
 
 pub type Result<T> = std::result::Result<T, KvsError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Cursor;
+
+    // fails every other call with `ErrorKind::Interrupted`
+    struct Interrupting<T> {
+        inner: T,
+        interrupt: bool,
+    }
+
+    impl<T> Interrupting<T> {
+        fn new(inner: T) -> Self {
+            Self {
+                inner,
+                interrupt: true,
+            }
+        }
+
+        fn interrupted(&mut self) -> bool {
+            self.interrupt = !self.interrupt;
+            !self.interrupt
+        }
+    }
+
+    impl<T: Read> Read for Interrupting<T> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.interrupted() {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            self.inner.read(buf)
+        }
+    }
+
+    impl<T: Write> Write for Interrupting<T> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.interrupted() {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    impl<T: Seek> Seek for Interrupting<T> {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    // fails the first `failures` writes and flushes with a transient error
+    struct Flaky<T> {
+        inner: T,
+        failures: u32,
+    }
+
+    impl<T> Flaky<T> {
+        fn fail(&mut self) -> io::Result<()> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "transient"));
+            }
+            Ok(())
+        }
+    }
+
+    impl<T: Write> Write for Flaky<T> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.fail()?;
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.fail()?;
+            self.inner.flush()
+        }
+    }
+
+    impl<T: Seek> Seek for Flaky<T> {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn reader_pool_bounds_open_files() -> Result<()> {
+        let dir = tempfile::TempDir::new().expect("unable to create temporary directory");
+        let mut pool = ReaderPool::new(dir.path().to_owned(), Arc::new(StdFileProvider), Some(3));
+        for gen in 1..=10 {
+            fs::write(log_path(dir.path(), gen), gen.to_string())?;
+            let reader = BufReaderWithPos::new(StdFileProvider.open(&log_path(dir.path(), gen))?)?;
+            pool.insert(gen, reader);
+            assert!(pool.open.len() <= 3);
+        }
+        for _ in 0..2 {
+            for gen in (1..=10).rev() {
+                let mut content = String::new();
+                let reader = pool.get_mut(gen)?;
+                reader.seek(SeekFrom::Start(0))?;
+                reader.read_to_string(&mut content)?;
+                assert_eq!(content, gen.to_string());
+                assert!(pool.open.len() <= 3);
+            }
+        }
+        // the most recently used readers stay open
+        assert!(pool.open.contains_key(&1));
+        assert!(pool.open.contains_key(&2));
+        pool.remove(1);
+        assert!(!pool.open.contains_key(&1));
+        assert_eq!(pool.gens().len(), 9);
+        Ok(())
+    }
+
+    #[test]
+    fn reader_retries_interrupted() -> Result<()> {
+        let mut reader = BufReaderWithPos::new(Interrupting::new(Cursor::new(b"value".to_vec())))?;
+        let mut buf = [0; 5];
+        assert_eq!(reader.read(&mut buf)?, 5);
+        assert_eq!(&buf, b"value");
+        assert_eq!(reader.pos, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn writer_retries_interrupted() -> Result<()> {
+        let mut writer = BufWriterWithPos::new(Interrupting::new(Cursor::new(Vec::new())))?;
+        // larger than the buffer so that the write reaches the inner writer directly
+        let buf = vec![1; 16 * 1024];
+        assert_eq!(writer.write(&buf)?, buf.len());
+        writer.flush()?;
+        assert_eq!(writer.pos, buf.len() as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn writer_retries_transient_errors() -> Result<()> {
+        let flaky = Flaky {
+            inner: Cursor::new(Vec::new()),
+            failures: 3,
+        };
+        let mut writer = BufWriterWithPos::new(flaky)?;
+        writer.retry = RetryPolicy {
+            retries: 3,
+            base_delay: Duration::from_millis(1),
+        };
+        let buf = vec![1; 16 * 1024];
+        assert_eq!(writer.write(&buf)?, buf.len());
+        writer.flush()?;
+        assert_eq!(writer.writer.get_ref().inner.get_ref(), &buf);
+
+        // without retries the first failure surfaces
+        let flaky = Flaky {
+            inner: Cursor::new(Vec::new()),
+            failures: 1,
+        };
+        let mut writer = BufWriterWithPos::new(flaky)?;
+        let err = writer.write(&buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        Ok(())
+    }
+
+    #[test]
+    fn failed_write_leaves_no_partial_record() -> Result<()> {
+        let dir = tempfile::TempDir::new().expect("unable to create temporary directory");
+        let mut store = KvStore::open(dir.path())?;
+        store.set("key0".to_owned(), "value0".to_owned())?;
+        let log_len = || fs::metadata(log_path(dir.path(), 1)).unwrap().len();
+        let len = log_len();
+
+        // half a record reaches the file before the write fails
+        let res: Result<()> = store.write_atomic(|store| {
+            let writer = store.writer()?;
+            writer.write_all(br#"{"Set":{"key":"key1","#)?;
+            writer.flush()?;
+            writer.write_all(br#""value":"value1"}}"#)?;
+            Err(io::Error::other("injected").into())
+        });
+        assert!(res.is_err());
+        assert_eq!(log_len(), len);
+        assert_eq!(store.get("key1".to_owned())?, None);
+
+        // the buffered rest of the record is not written by later writes
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        drop(store);
+        let mut store = KvStore::open(dir.path())?;
+        assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.uncompacted, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn failed_truncate_moves_to_a_new_generation() -> Result<()> {
+        let dir = tempfile::TempDir::new().expect("unable to create temporary directory");
+        let mut store = KvStore::open(dir.path())?;
+        store.set("key0".to_owned(), "value0".to_owned())?;
+        let log_len = || fs::metadata(log_path(dir.path(), 1)).unwrap().len();
+        let len = log_len();
+
+        // a writer on a file opened for reading can't cut it back
+        let res: Result<()> = store.write_atomic(|store| {
+            let file = StdFileProvider.open(&log_path(dir.path(), 1))?;
+            store.writer = Some(BufWriterWithPos::new(file)?);
+            Err(io::Error::other("injected").into())
+        });
+        assert!(res.is_err());
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(store.current_gen, 2);
+        assert_eq!(log_len(), len);
+
+        drop(store);
+        let mut store = KvStore::open(dir.path())?;
+        assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn generation_overflow() -> Result<()> {
+        let dir = tempfile::TempDir::new().expect("unable to create temporary directory");
+        let mut store = KvStore::open(dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.current_gen = u64::MAX - 2;
+        assert_eq!(store.rotate()?, u64::MAX - 1);
+        assert_eq!(store.compact(), Err(KvsError::GenerationOverflow));
+        assert_eq!(store.rotate()?, u64::MAX);
+        assert_eq!(store.rotate(), Err(KvsError::GenerationOverflow));
+        assert_eq!(store.clear(), Err(KvsError::GenerationOverflow));
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        drop(store);
+
+        // the next generation after the last log file doesn't exist either
+        assert_eq!(
+            KvStore::open(dir.path()).err(),
+            Some(KvsError::GenerationOverflow)
+        );
+        Ok(())
+    }
+}
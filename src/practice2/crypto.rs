@@ -0,0 +1,61 @@
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+use super::{KvsError, Result};
+
+const NONCE_LEN: usize = 12;
+
+// encrypts values with ChaCha20-Poly1305
+// every value carries its own random nonce and is bound to its key
+#[derive(Clone)]
+pub(super) struct Cipher(ChaCha20Poly1305);
+
+impl Cipher {
+    pub(super) fn new(key: &[u8; 32]) -> Self {
+        Self(ChaCha20Poly1305::new(Key::from_slice(key)))
+    }
+
+    // returns the nonce followed by the ciphertext, hex encoded
+    pub(super) fn encrypt(&self, key: &str, value: &str) -> String {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: value.as_bytes(),
+            aad: key.as_bytes(),
+        };
+        let ciphertext = self
+            .0
+            .encrypt(&nonce, payload)
+            .expect("value too large to encrypt");
+        let mut encoded = to_hex(&nonce);
+        encoded.push_str(&to_hex(&ciphertext));
+        encoded
+    }
+
+    pub(super) fn decrypt(&self, key: &str, value: &str) -> Result<String> {
+        let bytes = from_hex(value).ok_or(KvsError::DecryptionFailed)?;
+        if bytes.len() < NONCE_LEN {
+            return Err(KvsError::DecryptionFailed);
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: key.as_bytes(),
+        };
+        let plaintext = self
+            .0
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| KvsError::DecryptionFailed)?;
+        String::from_utf8(plaintext).map_err(|_| KvsError::DecryptionFailed)
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
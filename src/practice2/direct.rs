@@ -0,0 +1,234 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use super::{FileProvider, KvsError, LogFile, Result, StdFileProvider};
+
+// direct writes must start at, and cover, whole blocks of this size
+const BLOCK_SIZE: usize = 4096;
+// bytes collected before they are written out, unless flushed earlier
+const BUFFER_SIZE: usize = 256 * BLOCK_SIZE;
+
+// files on the local filesystem written with `O_DIRECT`, bypassing the page cache
+// reads and every other operation go through `StdFileProvider`
+pub(super) struct DirectFileProvider;
+
+impl DirectFileProvider {
+    // fails with `KvsError::DirectIoUnsupported` if the filesystem of `dir` refuses `O_DIRECT`
+    pub(super) fn new(dir: &Path) -> Result<Self> {
+        let probe = dir.join(".direct_io");
+        let opened = open_direct(&probe, false);
+        let _ = fs::remove_file(&probe);
+        match opened {
+            Ok(_) => Ok(Self),
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => Err(KvsError::DirectIoUnsupported),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+fn open_direct(path: &Path, truncate: bool) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(truncate)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+}
+
+impl FileProvider for DirectFileProvider {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn LogFile>> {
+        StdFileProvider.open(path)
+    }
+
+    fn append(&self, path: &Path) -> io::Result<Box<dyn LogFile>> {
+        Ok(Box::new(DirectFile::new(
+            path.to_owned(),
+            open_direct(path, false)?,
+        )?))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn LogFile>> {
+        Ok(Box::new(DirectFile::new(
+            path.to_owned(),
+            open_direct(path, true)?,
+        )?))
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        StdFileProvider.read(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        StdFileProvider.write(path, contents)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        StdFileProvider.rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        StdFileProvider.remove(path)
+    }
+
+    fn len(&self, path: &Path) -> io::Result<u64> {
+        StdFileProvider.len(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        StdFileProvider.exists(path)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        StdFileProvider.list(dir)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        StdFileProvider.create_dir_all(dir)
+    }
+
+    fn same_device(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        StdFileProvider.same_device(a, b)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        StdFileProvider.sync_dir(dir)
+    }
+}
+
+// a write-only file appended to in whole blocks from an aligned buffer
+// the last, partial block is padded when written and the file cut back to its
+// real length, then written again once more data follows
+struct DirectFile {
+    path: PathBuf,
+    file: File,
+    // backing memory, `buf` is its block aligned part
+    mem: Vec<u8>,
+    // where the aligned part of `mem` starts
+    offset: usize,
+    // bytes of the buffer in use, starting at `block_start` in the file
+    len: usize,
+    block_start: u64,
+}
+
+impl DirectFile {
+    fn new(path: PathBuf, file: File) -> io::Result<Self> {
+        let mem = vec![0; BUFFER_SIZE + BLOCK_SIZE];
+        let offset = mem.as_ptr().align_offset(BLOCK_SIZE);
+        let mut direct = Self {
+            path,
+            file,
+            mem,
+            offset,
+            len: 0,
+            block_start: 0,
+        };
+        let len = direct.file.metadata()?.len();
+        direct.reset(len)?;
+        Ok(direct)
+    }
+
+    fn buf(&mut self) -> &mut [u8] {
+        &mut self.mem[self.offset..self.offset + BUFFER_SIZE]
+    }
+
+    fn file_len(&self) -> u64 {
+        self.block_start + self.len as u64
+    }
+
+    // continue writing at `len`, with the partial block before it in the buffer
+    fn reset(&mut self, len: u64) -> io::Result<()> {
+        self.block_start = len - len % BLOCK_SIZE as u64;
+        self.len = (len - self.block_start) as usize;
+        if self.len > 0 {
+            // direct reads need alignment as well, the page cache is fine for a block
+            let (block_start, len) = (self.block_start, self.len);
+            let tail = File::open(&self.path)?;
+            tail.read_exact_at(&mut self.buf()[..len], block_start)?;
+        }
+        Ok(())
+    }
+
+    // write the whole buffer, keeping its partial last block for the next writes
+    fn write_out(&mut self) -> io::Result<()> {
+        if self.len == 0 {
+            return Ok(());
+        }
+        let (len, padded) = (self.len, self.len.div_ceil(BLOCK_SIZE) * BLOCK_SIZE);
+        let buf = &mut self.mem[self.offset..self.offset + BUFFER_SIZE];
+        buf[len..padded].iter_mut().for_each(|byte| *byte = 0);
+        self.file.write_all_at(&buf[..padded], self.block_start)?;
+        self.file.set_len(self.file_len())?;
+
+        let full = len - len % BLOCK_SIZE;
+        self.buf().copy_within(full..len, 0);
+        self.block_start += full as u64;
+        self.len -= full;
+        Ok(())
+    }
+}
+
+impl Write for DirectFile {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.len == BUFFER_SIZE {
+            self.write_out()?;
+        }
+        let n = data.len().min(BUFFER_SIZE - self.len);
+        let len = self.len;
+        self.buf()[len..len + n].copy_from_slice(&data[..n]);
+        self.len += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_out()
+    }
+}
+
+impl Read for DirectFile {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "direct log files are write only",
+        ))
+    }
+}
+
+impl Seek for DirectFile {
+    // only appending is supported, every seek stays at the end of the file
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let end = self.file_len();
+        match pos {
+            SeekFrom::Start(pos) if pos == end => Ok(end),
+            SeekFrom::Current(0) | SeekFrom::End(0) => Ok(end),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "direct log files only append",
+            )),
+        }
+    }
+}
+
+impl LogFile for DirectFile {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.write_out()?;
+        self.file.set_len(len)?;
+        self.reset(len)
+    }
+
+    fn sync_all(&mut self) -> io::Result<()> {
+        self.write_out()?;
+        self.file.sync_all()
+    }
+}
+
+impl Drop for DirectFile {
+    fn drop(&mut self) {
+        if let Err(e) = self.write_out() {
+            warn!("failed to write {}: {}", self.path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// an open file the store reads or writes a log through
+pub trait LogFile: Read + Write + Seek + Send {
+    // cut the file to `len` bytes
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+    // make the written data durable, nothing to do for files that don't outlive the process
+    fn sync_all(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl LogFile for File {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync_all(&mut self) -> io::Result<()> {
+        File::sync_all(self)
+    }
+}
+
+// every file operation of a store goes through this, so a store can live
+// somewhere other than a directory the process opens itself
+pub trait FileProvider: Send + Sync {
+    // open an existing file for reading
+    fn open(&self, path: &Path) -> io::Result<Box<dyn LogFile>>;
+    // open a file for appending, creating it if needed
+    fn append(&self, path: &Path) -> io::Result<Box<dyn LogFile>>;
+    // create an empty file for writing, truncating an existing one
+    fn create(&self, path: &Path) -> io::Result<Box<dyn LogFile>>;
+    // the whole content of a file
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+    // replace the content of a file, creating it if needed
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn remove(&self, path: &Path) -> io::Result<()>;
+    // size of a file in bytes
+    fn len(&self, path: &Path) -> io::Result<u64>;
+    fn exists(&self, path: &Path) -> bool;
+    // the files directly inside `dir`, without subdirectories
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()>;
+    // whether a file can be renamed from `a` to `b`
+    fn same_device(&self, _a: &Path, _b: &Path) -> io::Result<bool> {
+        Ok(true)
+    }
+    // make the files renamed into `dir` durable
+    fn sync_dir(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// files on the local filesystem through `std::fs`, used by default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdFileProvider;
+
+impl FileProvider for StdFileProvider {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn LogFile>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn append(&self, path: &Path) -> io::Result<Box<dyn LogFile>> {
+        Ok(Box::new(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        ))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn LogFile>> {
+        Ok(Box::new(
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(path)?,
+        ))
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        fs::write(path, contents)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn len(&self, path: &Path) -> io::Result<u64> {
+        Ok(fs::metadata(path)?.len())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_file() {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)
+    }
+
+    #[cfg(unix)]
+    fn same_device(&self, a: &Path, b: &Path) -> io::Result<bool> {
+        use std::os::unix::fs::MetadataExt;
+        Ok(fs::metadata(a)?.dev() == fs::metadata(b)?.dev())
+    }
+
+    #[cfg(unix)]
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        File::open(dir)?.sync_all()
+    }
+}
+
+type Content = Arc<Mutex<Vec<u8>>>;
+
+// files kept in memory, nothing touches the disk
+// clones share the same files, so a store can be reopened from them
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFileProvider {
+    files: Arc<Mutex<HashMap<PathBuf, Content>>>,
+}
+
+impl MemoryFileProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn content(&self, path: &Path) -> io::Result<Content> {
+        self.files
+            .lock()
+            .unwrap()
+            .get(path)
+            .cloned()
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn handle(content: Content, append: bool) -> Box<dyn LogFile> {
+        Box::new(MemoryFile {
+            content,
+            pos: 0,
+            append,
+        })
+    }
+}
+
+impl FileProvider for MemoryFileProvider {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn LogFile>> {
+        Ok(Self::handle(self.content(path)?, false))
+    }
+
+    fn append(&self, path: &Path) -> io::Result<Box<dyn LogFile>> {
+        let content = self
+            .files
+            .lock()
+            .unwrap()
+            .entry(path.to_owned())
+            .or_default()
+            .clone();
+        Ok(Self::handle(content, true))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn LogFile>> {
+        let content = Content::default();
+        self.files
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), content.clone());
+        Ok(Self::handle(content, false))
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        Ok(self.content(path)?.lock().unwrap().clone())
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        self.files
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), Arc::new(Mutex::new(contents.to_vec())));
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let content = files.remove(from).ok_or_else(|| not_found(from))?;
+        files.insert(to.to_owned(), content);
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.files
+            .lock()
+            .unwrap()
+            .remove(path)
+            .map(drop)
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn len(&self, path: &Path) -> io::Result<u64> {
+        Ok(self.content(path)?.lock().unwrap().len() as u64)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.files.lock().unwrap().contains_key(path)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(self
+            .files
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .cloned()
+            .collect())
+    }
+
+    fn create_dir_all(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} not found", path.display()),
+    )
+}
+
+// an open handle of a `MemoryFileProvider` file
+// the content is shared with the provider and every other handle of the file
+struct MemoryFile {
+    content: Content,
+    pos: u64,
+    // every write goes to the end of the file
+    append: bool,
+}
+
+impl Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let content = self.content.lock().unwrap();
+        let start = (self.pos as usize).min(content.len());
+        let len = buf.len().min(content.len() - start);
+        buf[..len].copy_from_slice(&content[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut content = self.content.lock().unwrap();
+        if self.append {
+            self.pos = content.len() as u64;
+        }
+        let start = self.pos as usize;
+        if content.len() < start + buf.len() {
+            content.resize(start + buf.len(), 0);
+        }
+        content[start..start + buf.len()].copy_from_slice(buf);
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(pos) => {
+                self.pos = pos;
+                return Ok(pos);
+            }
+            SeekFrom::End(offset) => (self.content.lock().unwrap().len() as u64, offset),
+            SeekFrom::Current(offset) => (self.pos, offset),
+        };
+        self.pos = base.checked_add_signed(offset).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek before start of file")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+impl LogFile for MemoryFile {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.content.lock().unwrap().resize(len as usize, 0);
+        Ok(())
+    }
+}
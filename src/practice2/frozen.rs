@@ -0,0 +1,146 @@
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::{KvStoreOptions, KvsError, LogFile, Result};
+
+// first and last bytes of a frozen store
+const MAGIC: &[u8; 8] = b"KVSFRZ01";
+// index offset, entry count and magic
+const FOOTER_LEN: u64 = 8 + 8 + 8;
+
+// an immutable, sorted archive of a store written by `KvStore::freeze`
+// layout: magic, the entries in key order as `key len, value len, key, value`
+// (lengths as little-endian u32), the u64 offset of every entry, and a footer
+// with the offset of that index, the number of entries and the magic again
+// lookups binary-search the index, so nothing is loaded on open
+pub struct FrozenKvStore {
+    file: BufReader<Box<dyn LogFile>>,
+    index_offset: u64,
+    len: u64,
+}
+
+impl FrozenKvStore {
+    // open the frozen store at `path`, `KvsError::InvalidFrozen` if it isn't one
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::open_with_options(path, KvStoreOptions::default())
+    }
+
+    // like `open`, reading through the file provider of `options`
+    pub fn open_with_options(path: &Path, options: KvStoreOptions) -> Result<Self> {
+        let mut file = BufReader::new(options.files.open(path)?);
+        let size = file.seek(SeekFrom::End(0))?;
+        if size < MAGIC.len() as u64 + FOOTER_LEN {
+            return Err(invalid("file too short"));
+        }
+        let mut magic = [0; 8];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("bad header"));
+        }
+        file.seek(SeekFrom::Start(size - FOOTER_LEN))?;
+        let index_offset = read_u64(&mut file)?;
+        let len = read_u64(&mut file)?;
+        file.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("bad footer"));
+        }
+        let index_end = len
+            .checked_mul(8)
+            .and_then(|index_len| index_len.checked_add(index_offset));
+        if index_offset < MAGIC.len() as u64 || index_end != Some(size - FOOTER_LEN) {
+            return Err(invalid("index out of bounds"));
+        }
+        Ok(Self {
+            file,
+            index_offset,
+            len,
+        })
+    }
+
+    // number of keys
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // get the value of the given key, `None` if it was not in the store
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        let (mut lo, mut hi) = (0, self.len);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            self.file
+                .seek(SeekFrom::Start(self.index_offset + mid * 8))?;
+            let offset = read_u64(&mut self.file)?;
+            self.file.seek(SeekFrom::Start(offset))?;
+            let key_len = read_u32(&mut self.file)?;
+            let value_len = read_u32(&mut self.file)?;
+            let entry_key = read_string(&mut self.file, key_len)?;
+            match entry_key.as_str().cmp(key) {
+                Ordering::Less => lo = mid + 1,
+                Ordering::Greater => hi = mid,
+                Ordering::Equal => {
+                    return Ok(Some(read_string(&mut self.file, value_len)?));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+// write the entries, which must be sorted by key, in the frozen layout
+pub(super) fn write(
+    w: &mut impl Write,
+    entries: impl Iterator<Item = Result<(String, String)>>,
+) -> Result<()> {
+    w.write_all(MAGIC)?;
+    let mut pos = MAGIC.len() as u64;
+    let mut offsets = Vec::new();
+    for entry in entries {
+        let (key, value) = entry?;
+        offsets.push(pos);
+        for part in [&key, &value] {
+            let len = u32::try_from(part.len()).map_err(|_| invalid("entry too large"))?;
+            w.write_all(&len.to_le_bytes())?;
+        }
+        w.write_all(key.as_bytes())?;
+        w.write_all(value.as_bytes())?;
+        pos += 8 + key.len() as u64 + value.len() as u64;
+    }
+    for offset in &offsets {
+        w.write_all(&offset.to_le_bytes())?;
+    }
+    w.write_all(&pos.to_le_bytes())?;
+    w.write_all(&(offsets.len() as u64).to_le_bytes())?;
+    w.write_all(MAGIC)?;
+    Ok(())
+}
+
+fn invalid(message: &str) -> KvsError {
+    KvsError::InvalidFrozen {
+        message: message.to_owned(),
+    }
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_string(r: &mut impl Read, len: u32) -> Result<String> {
+    let mut buf = vec![0; len as usize];
+    r.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|_| invalid("entry is not valid UTF-8"))
+}
@@ -0,0 +1,74 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::{debug, warn};
+
+use super::KvStore;
+
+// bounds of the time between two checks of the store, the lower one keeps a
+// zero idle time from spinning
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(1);
+const MAX_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// compacts a shared store on a background thread, but only once no write
+// arrived for `idle`, so bursts of writes never wait for a compaction
+// combine it with `KvStoreOptions::auto_compact(false)` to keep compaction
+// off the write path entirely; the thread stops when this is dropped
+pub struct IdleCompactor {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl IdleCompactor {
+    // start watching `store`, idle time is measured with the store's clock
+    pub fn spawn(store: Arc<Mutex<KvStore>>, idle: Duration) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let poll_interval = (idle / 4).clamp(MIN_POLL_INTERVAL, MAX_POLL_INTERVAL);
+        let handle = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                // the write the last compaction ran after, a minor compaction may leave
+                // stale data behind and isn't repeated until the next idle period
+                let mut compacted_after = None;
+                while !stop.load(Ordering::SeqCst) {
+                    thread::sleep(poll_interval);
+                    let mut store = store.lock().unwrap();
+                    if store.uncompacted == 0 || compacted_after == Some(store.last_write) {
+                        continue;
+                    }
+                    // a clock moved backwards counts as a fresh write
+                    let idle_for = store
+                        .options
+                        .clock
+                        .now()
+                        .duration_since(store.last_write)
+                        .unwrap_or_default();
+                    if idle_for >= idle {
+                        debug!("compacting after {:?} without writes", idle_for);
+                        if let Err(e) = store.compact() {
+                            warn!("idle compaction failed: {}", e);
+                        }
+                        compacted_after = Some(store.last_write);
+                    }
+                }
+            })
+        };
+        Self {
+            stop,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for IdleCompactor {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                warn!("idle compaction thread panicked");
+            }
+        }
+    }
+}
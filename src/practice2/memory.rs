@@ -0,0 +1,66 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use super::{glob_match, KvsEngine, KvsError, Result};
+
+// a store that keeps every value in memory and never touches the disk
+// for tests and caches that want the `KvsEngine` api without any io
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    map: BTreeMap<String, String>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl KvsEngine for MemoryStore {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.map.insert(key, value);
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self.map.get(&key).cloned())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.map
+            .remove(&key)
+            .map(|_| ())
+            .ok_or(KvsError::KeyNotFound)
+    }
+
+    fn contains_keys(&self, keys: &[String]) -> Vec<bool> {
+        keys.iter().map(|key| self.map.contains_key(key)).collect()
+    }
+
+    fn scan_pattern(&mut self, pattern: &str, limit: Option<usize>) -> Result<Vec<String>> {
+        Ok(self
+            .map
+            .keys()
+            .filter(|key| glob_match(pattern, key))
+            .take(limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect())
+    }
+
+    fn range(
+        &mut self,
+        start: &str,
+        end: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        if start >= end {
+            return Ok(Vec::new());
+        }
+        Ok(self
+            .map
+            .range::<str, _>((Bound::Included(start), Bound::Excluded(end)))
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+}
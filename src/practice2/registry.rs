@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use super::{KvStore, KvsEngine, KvsError, Result};
+
+// opens an engine stored at the given path
+pub type EngineFactory = Arc<dyn Fn(&Path) -> Result<Box<dyn KvsEngine>> + Send + Sync>;
+
+// the engines known by name, `kvs` for `KvStore` from the start
+fn registry() -> &'static Mutex<HashMap<String, EngineFactory>> {
+    static REGISTRY: OnceLock<Mutex<HashMap<String, EngineFactory>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let mut engines = HashMap::new();
+        let kvs: EngineFactory = Arc::new(|path| Ok(Box::new(KvStore::open(path)?)));
+        engines.insert("kvs".to_owned(), kvs);
+        Mutex::new(engines)
+    })
+}
+
+// make an engine available to `open_engine` under `name`, replacing one registered before
+pub fn register_engine(
+    name: impl Into<String>,
+    factory: impl Fn(&Path) -> Result<Box<dyn KvsEngine>> + Send + Sync + 'static,
+) {
+    registry()
+        .lock()
+        .unwrap()
+        .insert(name.into(), Arc::new(factory));
+}
+
+// open the engine registered under `name` at `path`
+// `KvsError::UnknownEngine` if no engine has that name
+pub fn open_engine(name: &str, path: &Path) -> Result<Box<dyn KvsEngine>> {
+    let factory = registry()
+        .lock()
+        .unwrap()
+        .get(name)
+        .cloned()
+        .ok_or_else(|| KvsError::UnknownEngine(name.to_owned()))?;
+    factory(path)
+}
+
+// names of the registered engines, in order
+pub fn engine_names() -> Vec<String> {
+    let mut names = registry()
+        .lock()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect::<Vec<_>>();
+    names.sort_unstable();
+    names
+}
@@ -0,0 +1,116 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+// derives the field a value is indexed by, `None` leaves the value out of the index
+pub type FieldExtractor = Box<dyn Fn(&str) -> Option<String> + Send>;
+
+// fields extracted from one value, one per index in the order of their names
+pub(super) type Fields = Vec<Option<String>>;
+
+struct SecondaryIndex {
+    extract: FieldExtractor,
+    // field value to the keys holding it
+    keys: BTreeMap<String, BTreeSet<Box<str>>>,
+    // key to the field value it is listed under
+    fields: HashMap<Box<str>, String>,
+}
+
+impl SecondaryIndex {
+    fn insert(&mut self, key: &str, field: Option<String>) {
+        self.remove(key);
+        if let Some(field) = field {
+            self.keys
+                .entry(field.clone())
+                .or_default()
+                .insert(key.into());
+            self.fields.insert(key.into(), field);
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(field) = self.fields.remove(key) {
+            let keys = self.keys.get_mut(&field).expect("field not indexed");
+            keys.remove(key);
+            if keys.is_empty() {
+                self.keys.remove(&field);
+            }
+        }
+    }
+}
+
+// the secondary indexes of a store by name, kept in memory only
+// keys are the store's index keys, so folded in a case-insensitive store
+#[derive(Default)]
+pub(super) struct SecondaryIndexes {
+    indexes: BTreeMap<String, SecondaryIndex>,
+}
+
+impl SecondaryIndexes {
+    // add or replace the index `name`, filled with the given entries
+    pub(super) fn create(
+        &mut self,
+        name: &str,
+        extract: FieldExtractor,
+        entries: Vec<(Box<str>, String)>,
+    ) {
+        let mut index = SecondaryIndex {
+            extract,
+            keys: BTreeMap::new(),
+            fields: HashMap::new(),
+        };
+        for (key, value) in entries {
+            let field = (index.extract)(&value);
+            index.insert(&key, field);
+        }
+        self.indexes.insert(name.to_owned(), index);
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.indexes.is_empty()
+    }
+
+    // fields of `value` for every index, taken before the value is written
+    pub(super) fn extract(&self, value: &str) -> Fields {
+        self.indexes
+            .values()
+            .map(|index| (index.extract)(value))
+            .collect()
+    }
+
+    // list `key` under the fields extracted from its new value
+    pub(super) fn insert(&mut self, key: &str, fields: Fields) {
+        for (index, field) in self.indexes.values_mut().zip(fields) {
+            index.insert(key, field);
+        }
+    }
+
+    pub(super) fn remove(&mut self, key: &str) {
+        for index in self.indexes.values_mut() {
+            index.remove(key);
+        }
+    }
+
+    // drop every entry, the indexes themselves stay
+    pub(super) fn clear(&mut self) {
+        for index in self.indexes.values_mut() {
+            index.keys.clear();
+            index.fields.clear();
+        }
+    }
+
+    // the keys listed under `field` in ascending order, `None` if there is no index `name`
+    pub(super) fn query(&self, name: &str, field: &str) -> Option<Vec<String>> {
+        let index = self.indexes.get(name)?;
+        Some(index.keys.get(field).map_or_else(Vec::new, |keys| {
+            keys.iter().map(|key| key.to_string()).collect()
+        }))
+    }
+
+    // remove every index, returning their names and extractors for rebuilding them
+    pub(super) fn take(&mut self) -> Vec<(String, FieldExtractor)> {
+        let indexes = std::mem::take(&mut self.indexes);
+        indexes
+            .into_iter()
+            .map(|(name, index)| (name, index.extract))
+            .collect()
+    }
+}
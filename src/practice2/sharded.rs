@@ -0,0 +1,240 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::thread;
+
+use super::{KvStore, KvStoreOptions, KvsEngine, KvsError, Result};
+
+// file recording the number of shards a sharded store was created with
+const SHARDS: &str = "SHARDS";
+// points every shard takes on the hash ring
+const VIRTUAL_NODES: usize = 128;
+
+// consistent hashing of keys onto shards
+// every shard owns `virtual_nodes` points on a ring of 64-bit hashes and a key
+// belongs to the shard of the first point at or after the key's hash, so adding
+// a shard only moves the keys that now fall before one of its points
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    points: BTreeMap<u64, usize>,
+    shards: usize,
+}
+
+impl HashRing {
+    pub fn new(shards: usize, virtual_nodes: usize) -> Self {
+        assert!(
+            shards > 0 && virtual_nodes > 0,
+            "a ring needs at least one point"
+        );
+        let mut points = BTreeMap::new();
+        for shard in 0..shards {
+            for node in 0..virtual_nodes {
+                points.insert(hash(&format!("shard-{}-{}", shard, node)), shard);
+            }
+        }
+        Self { points, shards }
+    }
+
+    // number of shards on the ring
+    pub fn shards(&self) -> usize {
+        self.shards
+    }
+
+    // the points of the ring with the shard owning each, in ring order
+    pub fn points(&self) -> impl Iterator<Item = (u64, usize)> + '_ {
+        self.points.iter().map(|(&point, &shard)| (point, shard))
+    }
+
+    // index of the shard owning `key`
+    pub fn shard_of(&self, key: &str) -> usize {
+        let hash = hash(key);
+        self.points
+            .range((Bound::Included(hash), Bound::Unbounded))
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, &shard)| shard)
+            .expect("ring without points")
+    }
+}
+
+// FNV-1a rather than the std hasher, whose output may change between releases,
+// followed by a mixing step so that similar inputs spread over the whole ring
+fn hash(key: &str) -> u64 {
+    let hash = key.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    let hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    let hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+// a store spreading its keys over several `KvStore`s by consistent hashing
+// every shard lives in its own directory `shard-<n>` and compacts on its own;
+// the number of shards is fixed when the store is created
+pub struct ShardedKvStore {
+    shards: Vec<KvStore>,
+    ring: HashRing,
+    options: KvStoreOptions,
+}
+
+impl ShardedKvStore {
+    // open a store of `shards` shards in `path`, creating it if it does not exist
+    pub fn open(path: impl Into<PathBuf>, shards: usize) -> Result<Self> {
+        Self::open_with_options(path, shards, KvStoreOptions::default())
+    }
+
+    // open a store of `shards` shards, each opened with `options`
+    // fails with `KvsError::ConfigMismatch` if the store was created with another number of shards
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        shards: usize,
+        options: KvStoreOptions,
+    ) -> Result<Self> {
+        assert!(shards > 0, "a sharded store needs at least one shard");
+        let path = path.into();
+        let files = options.files.clone();
+        files.create_dir_all(&path)?;
+        let shards_path = path.join(SHARDS);
+        if files.exists(&shards_path) {
+            let stored = String::from_utf8_lossy(&files.read(&shards_path)?)
+                .trim()
+                .parse::<usize>()
+                .ok();
+            if stored != Some(shards) {
+                return Err(KvsError::ConfigMismatch {
+                    setting: "shards".to_owned(),
+                });
+            }
+        } else {
+            files.write(&shards_path, shards.to_string().as_bytes())?;
+        }
+        let shards = (0..shards)
+            .map(|n| KvStore::open_with_options(path.join(format!("shard-{}", n)), options.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        let ring = HashRing::new(shards.len(), VIRTUAL_NODES);
+        Ok(Self {
+            shards,
+            ring,
+            options,
+        })
+    }
+
+    // the underlying stores, in shard order
+    pub fn shards(&self) -> &[KvStore] {
+        &self.shards
+    }
+
+    // the ring placing the keys on the shards
+    pub fn ring(&self) -> &HashRing {
+        &self.ring
+    }
+
+    // index of the shard holding `key`
+    pub fn shard_of(&self, key: &str) -> usize {
+        self.ring.shard_of(&self.options.index_key(key))
+    }
+
+    fn shard(&mut self, key: &str) -> &mut KvStore {
+        let n = self.shard_of(key);
+        &mut self.shards[n]
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.shard(&key).set(key, value)
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.shard(&key).get(key)
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.shard(&key).remove(key)
+    }
+
+    // every key of every shard, in order
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys = self
+            .shards
+            .iter()
+            .flat_map(|shard| shard.index_map.keys().map(|key| key.to_string()))
+            .collect::<Vec<_>>();
+        keys.sort_unstable();
+        keys
+    }
+
+    // number of live keys over all shards
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(KvStore::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(KvStore::is_empty)
+    }
+
+    // compact every shard, each on its own thread
+    // returns the stale bytes reclaimed over all shards
+    pub fn compact(&mut self) -> Result<u64> {
+        thread::scope(|scope| {
+            let handles = self
+                .shards
+                .iter_mut()
+                .map(|shard| scope.spawn(move || shard.compact()))
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("compaction thread panicked"))
+                .sum()
+        })
+    }
+}
+
+impl KvsEngine for ShardedKvStore {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        ShardedKvStore::set(self, key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        ShardedKvStore::get(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        ShardedKvStore::remove(self, key)
+    }
+
+    fn contains_keys(&self, keys: &[String]) -> Vec<bool> {
+        keys.iter()
+            .map(|key| {
+                self.shards[self.shard_of(key)]
+                    .index_map
+                    .contains_key(&*self.options.index_key(key))
+            })
+            .collect()
+    }
+
+    // every shard is scanned up to `limit`, as any of them may hold the first keys
+    fn scan_pattern(&mut self, pattern: &str, limit: Option<usize>) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for shard in &mut self.shards {
+            keys.extend(shard.scan_pattern(pattern, limit)?);
+        }
+        keys.sort_unstable();
+        keys.truncate(limit.unwrap_or(usize::MAX));
+        Ok(keys)
+    }
+
+    fn range(
+        &mut self,
+        start: &str,
+        end: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        let mut entries = Vec::new();
+        for shard in &mut self.shards {
+            entries.extend(shard.range(start, end, limit)?);
+        }
+        let options = &self.options;
+        entries.sort_by(|(a, _), (b, _)| options.index_key(a).cmp(&options.index_key(b)));
+        entries.truncate(limit.unwrap_or(usize::MAX));
+        Ok(entries)
+    }
+}
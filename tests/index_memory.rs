@@ -0,0 +1,63 @@
+use kvs::practice2::CommandPos;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicIsize, Ordering};
+
+// keeps track of the bytes currently allocated by this test binary
+struct CountingAllocator;
+
+static ALLOCATED: AtomicIsize = AtomicIsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size() as isize, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size() as isize, Ordering::SeqCst);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn allocated() -> isize {
+    ALLOCATED.load(Ordering::SeqCst)
+}
+
+// Memory used by an index of 1M keys with boxed keys, as the store keeps it,
+// compared to indexing them by `String`.
+// Slow, run with `cargo test -- --ignored`.
+#[test]
+#[ignore]
+fn index_memory() {
+    const KEYS: usize = 1_000_000;
+    let key = |id| {
+        let mut key = format!("key{}", id);
+        key.shrink_to_fit();
+        key
+    };
+    let cmd_pos = |id| CommandPos {
+        gen: 1,
+        pos: id as u64,
+        len: 1,
+    };
+
+    let before = allocated();
+    let mut boxed_index = BTreeMap::new();
+    for id in 0..KEYS {
+        boxed_index.insert(key(id).into_boxed_str(), cmd_pos(id));
+    }
+    let boxed = allocated() - before;
+
+    let before = allocated();
+    let mut string_index = BTreeMap::new();
+    for id in 0..KEYS {
+        string_index.insert(key(id), cmd_pos(id));
+    }
+    let string = allocated() - before;
+
+    assert!(boxed < string);
+}
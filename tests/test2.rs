@@ -1,8 +1,18 @@
 use assert_cmd::prelude::*;
-use kvs::practice2::{KvStore, Result};
+use kvs::practice2::{
+    open_engine, register_engine, scan_raw, scan_raw_with_options, Clock, Command as LogCommand,
+    CommandPos, CompactionStrategy, ConflictPolicy, FileProvider, FrozenKvStore, GenerationReader,
+    HashRing, IdleCompactor, KvStore, KvStoreOptions, KvsEngine, KvsError, LogFile,
+    MemoryFileProvider, MemoryStore, MockClock, OnReadError, Result, ShardedKvStore,
+    StdFileProvider, SyncPolicy,
+};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -299,3 +309,3160 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// With auto compaction disabled, no compaction should happen until `compact` is called.
+#[test]
+fn auto_compact_disabled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().auto_compact(false);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    let log_files = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .count()
+    };
+
+    for iter in 0..200 {
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            let value = format!("{}", iter);
+            store.set(key, value)?;
+        }
+    }
+    assert_eq!(log_files(), 1);
+
+    store.compact()?;
+    assert_eq!(log_files(), 2);
+    for key_id in 0..1000 {
+        let key = format!("key{}", key_id);
+        assert_eq!(store.get(key)?, Some("199".to_owned()));
+    }
+    Ok(())
+}
+
+// `compact` should report the number of bytes it reclaimed from disk.
+#[test]
+fn compact_reports_reclaimed_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().auto_compact(false);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    let dir_size = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| entry.metadata().expect("fail to get metadata").len())
+            .sum::<u64>()
+    };
+
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    for key_id in 0..50 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    drop(store);
+
+    // reopen so that the reclaimed size is also checked against a replayed log
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let before = dir_size();
+    let reclaimed = store.compact()?;
+    let after = dir_size();
+    assert!(reclaimed > 0);
+    assert_eq!(reclaimed, before - after);
+
+    // nothing left to reclaim
+    assert_eq!(store.compact()?, 0);
+    Ok(())
+}
+
+// A read-only store should serve reads without creating or writing any file.
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let files = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .map(|entry| (entry.path().to_owned(), entry.metadata().unwrap().len()))
+            .collect::<Vec<_>>()
+    };
+
+    let before = files();
+    let mut store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(matches!(
+        store.set("key2".to_owned(), "value2".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(store.compact(), Err(KvsError::ReadOnly)));
+    drop(store);
+    assert_eq!(files(), before);
+
+    // a missing directory is not created
+    let missing = temp_dir.path().join("missing");
+    assert!(KvStore::open_read_only(&missing).is_err());
+    assert!(!missing.exists());
+    Ok(())
+}
+
+// Compaction callbacks should fire once per compaction, manual or automatic.
+#[test]
+fn compaction_callbacks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let starts = Arc::new(AtomicUsize::new(0));
+    let ends = Arc::new(AtomicUsize::new(0));
+    let reclaimed = Arc::new(AtomicU64::new(0));
+    let options = {
+        let starts = starts.clone();
+        let ends = ends.clone();
+        let reclaimed = reclaimed.clone();
+        KvStoreOptions::new()
+            .on_compaction_start(move |_| {
+                starts.fetch_add(1, Ordering::SeqCst);
+            })
+            .on_compaction_end(move |info| {
+                ends.fetch_add(1, Ordering::SeqCst);
+                reclaimed.fetch_add(info.reclaimed, Ordering::SeqCst);
+            })
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    let manual = store.compact()?;
+    assert_eq!(starts.load(Ordering::SeqCst), 1);
+    assert_eq!(ends.load(Ordering::SeqCst), 1);
+    assert_eq!(reclaimed.load(Ordering::SeqCst), manual);
+
+    for iter in 0..100 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    let compactions = ends.load(Ordering::SeqCst);
+    assert!(compactions > 1);
+    assert_eq!(starts.load(Ordering::SeqCst), compactions);
+    Ok(())
+}
+
+// Encrypted values should round-trip and never be stored in plain text.
+#[cfg(feature = "encryption")]
+#[test]
+fn encryption_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().encryption_key([7; 32]);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "secret value".to_owned())?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("secret value".to_owned())
+    );
+    drop(store);
+
+    for entry in WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
+        if entry.file_type().is_file() {
+            let content = std::fs::read_to_string(entry.path()).unwrap();
+            assert!(!content.contains("secret value"));
+        }
+    }
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("secret value".to_owned())
+    );
+    Ok(())
+}
+
+// Reopening an encrypted store with a different key should fail cleanly.
+#[cfg(feature = "encryption")]
+#[test]
+fn encryption_wrong_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().encryption_key([7; 32]);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "secret value".to_owned())?;
+    drop(store);
+
+    let options = KvStoreOptions::new().encryption_key([8; 32]);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert!(matches!(
+        store.get("key1".to_owned()),
+        Err(KvsError::DecryptionFailed)
+    ));
+    Ok(())
+}
+
+// An encrypted store should open read-only with its key.
+#[cfg(feature = "encryption")]
+#[test]
+fn encryption_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().encryption_key([7; 32]);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "secret value".to_owned())?;
+    drop(store);
+
+    assert_eq!(
+        KvStore::open_read_only(temp_dir.path()).err(),
+        Some(KvsError::ConfigMismatch {
+            setting: "encryption".to_owned()
+        })
+    );
+    let mut store = KvStore::open_read_only_with_options(temp_dir.path(), options)?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("secret value".to_owned())
+    );
+    assert_eq!(
+        store.set("key1".to_owned(), "value2".to_owned()),
+        Err(KvsError::ReadOnly)
+    );
+    Ok(())
+}
+
+// A minor compaction should merge the small generations and leave the large ones alone.
+#[test]
+fn minor_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .auto_compact(false)
+        .compaction_strategy(CompactionStrategy::Minor { max_size: 1024 });
+
+    let log_files = || {
+        let mut files = WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    };
+
+    // one large generation
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "x".repeat(100))?;
+    }
+    drop(store);
+
+    // many tiny generations, one per open
+    for key_id in 0..20 {
+        let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set(format!("key{}", key_id), format!("small{}", key_id))?;
+        store.remove(format!("key{}", 50 + key_id))?;
+        store.set("hot".to_owned(), format!("{}", key_id))?;
+    }
+    assert_eq!(log_files().len(), 21);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert!(store.compact()? > 0);
+    let files = log_files();
+    assert_eq!(files.len(), 3);
+    assert!(files.contains(&"1.log".to_owned()));
+    drop(store);
+
+    // removed keys must not come back from the untouched large generation
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("hot".to_owned())?, Some("19".to_owned()));
+
+    for key_id in 0..100 {
+        let expected = match key_id {
+            0..=19 => Some(format!("small{}", key_id)),
+            50..=69 => None,
+            _ => Some("x".repeat(100)),
+        };
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+    Ok(())
+}
+
+// A compaction should advance the generations by two.
+#[test]
+fn generations_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.generations(), vec![1]);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.generations(), vec![2, 3]);
+
+    store.compact()?;
+    assert_eq!(store.generations(), vec![4, 5]);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.generations(), vec![4, 5, 6]);
+    Ok(())
+}
+
+// Appending should create an absent key and extend an existing one.
+#[test]
+fn append_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.append("key1".to_owned(), "abc")?, 3);
+    assert_eq!(store.get("key1".to_owned())?, Some("abc".to_owned()));
+    assert_eq!(store.append("key1".to_owned(), "de")?, 5);
+    assert_eq!(store.get("key1".to_owned())?, Some("abcde".to_owned()));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("abcde".to_owned()));
+    Ok(())
+}
+
+// A store with many generations should open and read under a small reader cap.
+#[test]
+fn bounded_open_readers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_open_readers(4);
+    for key_id in 0..200 {
+        let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.generations().len(), 201);
+    for key_id in (0..200).rev() {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    store.compact()?;
+    for key_id in 0..200 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    Ok(())
+}
+
+// Renaming should move the value to the new key and survive a reopen.
+#[test]
+fn rename_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    store.rename("key1".to_owned(), "key2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        store.rename("key1".to_owned(), "key3".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A raw scan should yield the full history, not just the live entries.
+#[test]
+fn scan_raw_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let records = scan_raw(temp_dir.path())?
+        .map(|record| {
+            record.map(|(gen, cmd)| match cmd {
+                LogCommand::Set { key, value, .. } => (gen, key, Some(value)),
+                LogCommand::Remove { key } => (gen, key, None),
+                cmd => panic!("unexpected command {:?}", cmd),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let expected = vec![
+        (1, "key1".to_owned(), Some("value1".to_owned())),
+        (1, "key1".to_owned(), Some("value2".to_owned())),
+        (2, "key2".to_owned(), Some("value3".to_owned())),
+        (2, "key1".to_owned(), None),
+    ];
+    assert_eq!(records, expected);
+    Ok(())
+}
+
+// The initial generation should only apply to an empty directory.
+#[test]
+fn initial_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().initial_gen(100);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert!(temp_dir.path().join("100.log").exists());
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.generations(), vec![100, 101]);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// The existence flags should line up with the queried keys.
+#[test]
+fn contains_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    store.remove("key4".to_owned())?;
+
+    let keys = ["key3", "key2", "key1", "key4", "key1"]
+        .iter()
+        .map(|key| key.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        store.contains_keys(&keys),
+        vec![true, false, true, false, true]
+    );
+    assert!(store.contains_keys(&[]).is_empty());
+    Ok(())
+}
+
+// Opening a store without writing should not leave empty log files behind.
+#[test]
+fn no_empty_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().create_log_on_open(false);
+
+    let log_files = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .count()
+    };
+
+    for _ in 0..10 {
+        let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+    }
+    assert_eq!(log_files(), 0);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    for _ in 0..10 {
+        let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+    assert_eq!(log_files(), 1);
+
+    // the active generation after a compaction is created lazily as well
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    assert_eq!(log_files(), 1);
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    assert_eq!(log_files(), 2);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// `kvs get` should not leave an empty log file behind.
+#[test]
+fn cli_get_creates_no_log() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for _ in 0..3 {
+        Command::cargo_bin("kvs_2")
+            .unwrap()
+            .args(["get", "key1"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+}
+
+// All sets of a batch should be visible after it returns.
+#[test]
+fn set_transactional() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value0".to_owned())?;
+    store.set_transactional(vec![
+        ("key1".to_owned(), "value1".to_owned()),
+        ("key2".to_owned(), "value2".to_owned()),
+        ("key3".to_owned(), "value3".to_owned()),
+    ])?;
+    for id in 1..=3 {
+        assert_eq!(
+            store.get(format!("key{}", id))?,
+            Some(format!("value{}", id))
+        );
+    }
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for id in 1..=3 {
+        assert_eq!(
+            store.get(format!("key{}", id))?,
+            Some(format!("value{}", id))
+        );
+    }
+    store.compact()?;
+    for id in 1..=3 {
+        assert_eq!(
+            store.get(format!("key{}", id))?,
+            Some(format!("value{}", id))
+        );
+    }
+    Ok(())
+}
+
+// A batch cut short by a crash should not apply any of its sets.
+#[test]
+fn set_transactional_torn() -> Result<()> {
+    let entries = || {
+        (1..=3)
+            .map(|id| (format!("key{}", id), format!("value{}", id)))
+            .collect::<Vec<_>>()
+    };
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    let before = std::fs::metadata(temp_dir.path().join("1.log"))?.len();
+    store.set_transactional(entries())?;
+    drop(store);
+    let log = std::fs::read(temp_dir.path().join("1.log"))?;
+
+    // cut inside the first set, inside the last set, right before and inside the batch end
+    let end_len = "\"BatchEnd\"".len();
+    let cuts = vec![
+        before as usize + 20,
+        log.len() - end_len - 3,
+        log.len() - end_len,
+        log.len() - 2,
+    ];
+    for cut in cuts {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        std::fs::write(temp_dir.path().join("1.log"), &log[..cut])?;
+
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+        for id in 1..=3 {
+            assert_eq!(store.get(format!("key{}", id))?, None);
+        }
+
+        // the store stays usable after recovering
+        store.set_transactional(entries())?;
+        drop(store);
+        let mut store = KvStore::open(temp_dir.path())?;
+        for id in 1..=3 {
+            assert_eq!(
+                store.get(format!("key{}", id))?,
+                Some(format!("value{}", id))
+            );
+        }
+    }
+    Ok(())
+}
+
+// A read transaction should not see writes or compactions made after it started.
+#[test]
+fn read_transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut txn = store.read_tx()?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    store.compact()?;
+
+    assert_eq!(txn.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(txn.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(txn.get("key3")?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// A rate limited compaction should take at least as long as its rate allows.
+#[test]
+fn compaction_rate_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_rate_limit(100 * 1024);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id), "x".repeat(1024))?;
+    }
+    // The header isn't copied at the limited rate, only the records are.
+    let header = r#"{"kvs_log":1,"flags":0}"#;
+    let live = std::fs::metadata(temp_dir.path().join("1.log"))?.len() - header.len() as u64;
+
+    let start = Instant::now();
+    store.compact()?;
+    let min = Duration::from_secs_f64(live as f64 / (100.0 * 1024.0));
+    assert!(start.elapsed() >= min);
+    for key_id in 0..20 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("x".repeat(1024)));
+    }
+    Ok(())
+}
+
+// A store restored from a backup should hold the backed up data.
+#[test]
+fn restore_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store_dir = temp_dir.path().join("store");
+    let backup_dir = temp_dir.path().join("backup");
+    let mut store = KvStore::open(&store_dir)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.compact()?;
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    std::fs::create_dir(&backup_dir)?;
+    for entry in std::fs::read_dir(&store_dir)? {
+        let entry = entry?;
+        std::fs::copy(entry.path(), backup_dir.join(entry.file_name()))?;
+    }
+    std::fs::remove_dir_all(&store_dir)?;
+
+    KvStore::restore(&backup_dir, &store_dir)?;
+    let mut store = KvStore::open(&store_dir)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    // restoring over an existing store is refused
+    assert!(matches!(
+        KvStore::restore(&backup_dir, &store_dir),
+        Err(KvsError::RestoreTargetNotEmpty)
+    ));
+    Ok(())
+}
+
+// A restored store should keep the settings of the backed up one.
+#[test]
+fn restore_case_insensitive_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = temp_dir.path().join("backup");
+    let store_dir = temp_dir.path().join("store");
+    let options = KvStoreOptions::new().case_insensitive(true);
+    let mut store = KvStore::open_with_options(&backup_dir, options)?;
+    store.set("Key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    KvStore::restore(&backup_dir, &store_dir)?;
+    let mut store = KvStore::open(&store_dir)?;
+    assert_eq!(store.get("KEY1".to_owned())?, Some("value1".to_owned()));
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.len(), 1);
+    Ok(())
+}
+
+// A corrupt backup file should be reported and leave nothing behind.
+#[test]
+fn restore_corrupt_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = temp_dir.path().join("backup");
+    let store_dir = temp_dir.path().join("store");
+    for key_id in 0..3 {
+        let mut store = KvStore::open(&backup_dir)?;
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    std::fs::write(backup_dir.join("2.log"), "{\"Set\":garbage}{\"Set\":{}}")?;
+
+    match KvStore::restore(&backup_dir, &store_dir) {
+        Err(KvsError::InvalidLog { gen, .. }) => assert_eq!(gen, 2),
+        res => panic!("unexpected restore result {:?}", res),
+    }
+    assert_eq!(std::fs::read_dir(&store_dir)?.count(), 0);
+    Ok(())
+}
+
+// Errors should compare by variant, and wrapped errors by kind.
+#[test]
+fn error_equality() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.remove("key1".to_owned()).unwrap_err(),
+        KvsError::KeyNotFound
+    );
+    assert_ne!(KvsError::KeyNotFound, KvsError::ReadOnly);
+
+    let not_found = || KvsError::from(std::io::Error::from(std::io::ErrorKind::NotFound));
+    assert_eq!(not_found(), not_found());
+    assert_eq!(
+        not_found(),
+        KvsError::from(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "other message"
+        ))
+    );
+    assert_ne!(
+        not_found(),
+        KvsError::from(std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+    );
+
+    let syntax = |s: &str| KvsError::from(serde_json::from_str::<u64>(s).unwrap_err());
+    assert_eq!(syntax("x"), syntax("y"));
+    assert_ne!(syntax("x"), syntax("[1"));
+    assert_ne!(syntax("x"), not_found());
+    Ok(())
+}
+
+// Writes after a rotation should land in the new generation.
+#[test]
+fn rotate_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.rotate()?, 2);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.generations(), vec![1, 2]);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    let gens = scan_raw(temp_dir.path())?
+        .map(|record| {
+            record.map(|(gen, cmd)| match cmd {
+                LogCommand::Set { key, .. } => (key, gen),
+                cmd => panic!("unexpected command {:?}", cmd),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(gens, vec![("key1".to_owned(), 1), ("key2".to_owned(), 2)]);
+
+    let mut store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.rotate(), Err(KvsError::ReadOnly));
+    Ok(())
+}
+
+// Reading a generation directly should return the value recorded there.
+#[test]
+fn get_at_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .auto_compact(false)
+        .compaction_strategy(CompactionStrategy::Minor { max_size: 1024 });
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id), "x".repeat(100))?;
+    }
+    store.set("key1".to_owned(), "old".to_owned())?;
+    store.rotate()?;
+    store.set("key1".to_owned(), "new".to_owned())?;
+    store.set("key1".to_owned(), "newer".to_owned())?;
+
+    assert_eq!(store.get_at_gen("key1", 1)?, Some("old".to_owned()));
+    assert_eq!(store.get_at_gen("key1", 2)?, Some("newer".to_owned()));
+    assert_eq!(store.get_at_gen("key2", 2)?, None);
+
+    // the minor compaction moves key1 but keeps the large first generation
+    store.compact()?;
+    assert_eq!(store.generations(), vec![1, 3, 4]);
+    assert_eq!(store.get_at_gen("key1", 1)?, Some("old".to_owned()));
+    assert_eq!(store.get_at_gen("key1", 3)?, Some("newer".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("newer".to_owned()));
+    assert_eq!(
+        store.get_at_gen("key1", 2),
+        Err(KvsError::GenerationNotFound(2))
+    );
+    Ok(())
+}
+
+// Reopening should follow the settings recorded in the manifest.
+#[test]
+fn manifest_enforces_config() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let manifest_path = temp_dir.path().join("MANIFEST");
+    let options =
+        KvStoreOptions::new().compaction_strategy(CompactionStrategy::Minor { max_size: 1024 });
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id), "x".repeat(100))?;
+    }
+    drop(store);
+    assert!(manifest_path.exists());
+
+    // a mismatch is rejected on request
+    let options = KvStoreOptions::new().reject_config_mismatch(true);
+    assert_eq!(
+        KvStore::open_with_options(temp_dir.path(), options).err(),
+        Some(KvsError::ConfigMismatch {
+            setting: "compaction_strategy".to_owned()
+        })
+    );
+
+    // by default the recorded minor strategy is used and keeps the large generation
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact()?;
+    assert!(store.generations().contains(&1));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // a different log format is never accepted
+    let manifest = std::fs::read_to_string(&manifest_path)?;
+    std::fs::write(&manifest_path, manifest.replace("\"json\"", "\"bincode\""))?;
+    assert_eq!(
+        KvStore::open(temp_dir.path()).err(),
+        Some(KvsError::ConfigMismatch {
+            setting: "format".to_owned()
+        })
+    );
+    Ok(())
+}
+
+// Keys should be matched against `*` and `?` wildcards.
+#[test]
+fn scan_pattern() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key in [
+        "user:1:active",
+        "user:2:inactive",
+        "user:10:active",
+        "admin:1:active",
+        "user",
+    ] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+    store.remove("user:10:active".to_owned())?;
+
+    assert_eq!(
+        store.scan_pattern("*:active", None)?,
+        vec!["admin:1:active", "user:1:active"]
+    );
+    assert_eq!(
+        store.scan_pattern("user:?:*", None)?,
+        vec!["user:1:active", "user:2:inactive"]
+    );
+    assert_eq!(
+        store.scan_pattern("user*", None)?,
+        vec!["user", "user:1:active", "user:2:inactive"]
+    );
+    assert_eq!(store.scan_pattern("user", None)?, vec!["user"]);
+    assert!(store.scan_pattern("user?", None)?.is_empty());
+    assert_eq!(store.scan_pattern("*", None)?.len(), 4);
+    Ok(())
+}
+
+// Reverse iteration should yield every entry in descending key order.
+#[test]
+fn iter_rev() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{:03}", key_id), format!("value{}", key_id))?;
+    }
+    store.set("key050".to_owned(), "updated".to_owned())?;
+    store.remove("key010".to_owned())?;
+
+    let entries = store.iter_rev().collect::<Result<Vec<_>>>()?;
+    assert_eq!(entries.len(), 99);
+    assert!(entries.windows(2).all(|pair| pair[0].0 > pair[1].0));
+
+    let mut forward = Vec::new();
+    for key in store.scan_pattern("*", None)? {
+        let value = store.get(key.clone())?.expect("listed key has a value");
+        forward.push((key, value));
+    }
+    forward.reverse();
+    assert_eq!(entries, forward);
+    assert!(entries.contains(&("key050".to_owned(), "updated".to_owned())));
+    Ok(())
+}
+
+// Closing a store with `compact_on_close` should leave only live data on disk.
+#[test]
+fn compact_on_close() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_size = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum::<u64>()
+    };
+    let options = KvStoreOptions::new().compact_on_close(true);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    let before = log_size();
+    store.close()?;
+    let after = log_size();
+    assert!(after * 5 < before, "{} bytes left of {}", after, before);
+
+    // dropping compacts as well
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "latest".to_owned())?;
+    }
+    drop(store);
+    assert_eq!(log_size(), after);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key42".to_owned())?, Some("latest".to_owned()));
+    Ok(())
+}
+
+fn exercise_engine(engine: &mut (impl KvsEngine + ?Sized)) -> Result<()> {
+    for key_id in 0..10 {
+        engine.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    engine.set("key3".to_owned(), "updated".to_owned())?;
+    engine.remove("key5".to_owned())?;
+    assert_eq!(engine.remove("key5".to_owned()), Err(KvsError::KeyNotFound));
+    assert_eq!(engine.get("key3".to_owned())?, Some("updated".to_owned()));
+    assert_eq!(engine.get("key5".to_owned())?, None);
+    assert_eq!(
+        engine.contains_keys(&["key1".to_owned(), "key5".to_owned()]),
+        vec![true, false]
+    );
+    assert_eq!(engine.scan_pattern("key?", None)?.len(), 9);
+    assert_eq!(
+        engine.range("key3", "key7", None)?,
+        vec![
+            ("key3".to_owned(), "updated".to_owned()),
+            ("key4".to_owned(), "value4".to_owned()),
+            ("key6".to_owned(), "value6".to_owned()),
+        ]
+    );
+    assert!(engine.range("key7", "key3", None)?.is_empty());
+    assert_eq!(
+        engine.range("key3", "key7", Some(2))?,
+        vec![
+            ("key3".to_owned(), "updated".to_owned()),
+            ("key4".to_owned(), "value4".to_owned()),
+        ]
+    );
+    assert_eq!(engine.scan_pattern("key?", Some(2))?, vec!["key0", "key1"]);
+    Ok(())
+}
+
+// The in-memory engine should behave like the on-disk one without any files.
+#[test]
+fn memory_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    exercise_engine(&mut store)?;
+    exercise_engine(&mut MemoryStore::new())?;
+    Ok(())
+}
+
+// Compaction should fire after exactly the configured number of writes.
+#[test]
+fn compact_every_n_ops() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let compactions = Arc::new(AtomicUsize::new(0));
+    let counter = compactions.clone();
+    let options = KvStoreOptions::new()
+        .compact_every_n_ops(10)
+        .on_compaction_start(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..9 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    assert_eq!(compactions.load(Ordering::SeqCst), 0);
+    store.remove("key0".to_owned())?;
+    assert_eq!(compactions.load(Ordering::SeqCst), 1);
+
+    // a manual compaction restarts the count
+    for key_id in 0..5 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    store.compact()?;
+    assert_eq!(compactions.load(Ordering::SeqCst), 2);
+    for key_id in 0..9 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    assert_eq!(compactions.load(Ordering::SeqCst), 2);
+    store.set("key9".to_owned(), "value".to_owned())?;
+    assert_eq!(compactions.load(Ordering::SeqCst), 3);
+    Ok(())
+}
+
+// Compaction should keep the configured number of recent versions per key.
+#[test]
+fn keep_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().keep_versions(3);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for version in 1..=5 {
+        store.set("key1".to_owned(), format!("value{}", version))?;
+    }
+    store.set("key2".to_owned(), "value".to_owned())?;
+    store.set("key3".to_owned(), "old".to_owned())?;
+    store.set("key3".to_owned(), "new".to_owned())?;
+    store.remove("key3".to_owned())?;
+    store.compact()?;
+
+    let check = |store: &mut KvStore| -> Result<()> {
+        assert_eq!(store.get_version("key1", 0)?, Some("value5".to_owned()));
+        assert_eq!(store.get_version("key1", 1)?, Some("value4".to_owned()));
+        assert_eq!(store.get_version("key1", 2)?, Some("value3".to_owned()));
+        assert_eq!(store.get_version("key1", 3)?, None);
+        assert_eq!(store.get_version("key2", 1)?, None);
+        assert_eq!(store.get_version("key3", 1)?, None);
+        Ok(())
+    };
+    check(&mut store)?;
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    check(&mut store)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value5".to_owned()));
+    Ok(())
+}
+
+// A write to a full disk should fail with `DiskFull` and leave the key unset.
+#[cfg(target_os = "linux")]
+#[test]
+fn disk_full() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // every write to /dev/full fails with ENOSPC
+    std::os::unix::fs::symlink("/dev/full", temp_dir.path().join("1.log"))?;
+    let options = KvStoreOptions::new().create_log_on_open(false);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(
+        store.set("key1".to_owned(), "value1".to_owned()),
+        Err(KvsError::DiskFull)
+    );
+    assert_eq!(store.contains_keys(&["key1".to_owned()]), vec![false]);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// The metrics should be rendered in the prometheus text format.
+#[test]
+fn prometheus_metrics() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_open_readers(1);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value1".to_owned())?;
+    store.rotate()?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3".to_owned())?;
+    // The first read of key1 opens its log again, the second finds it open.
+    store.get("key1".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.get("key4".to_owned())?;
+
+    let output = store.prometheus_metrics();
+    let samples = output
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(|line| {
+            let (name, value) = line.split_once(' ').expect("sample without value");
+            (name, value.parse::<f64>().expect("non-numeric sample"))
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        samples,
+        vec![
+            ("kvs_live_keys", 2.0),
+            ("kvs_uncompacted_bytes", 103.0),
+            ("kvs_generations", 2.0),
+            ("kvs_writes_since_compaction", 5.0),
+            ("kvs_gets_total", 3.0),
+            ("kvs_sets_total", 4.0),
+            ("kvs_removes_total", 1.0),
+            ("kvs_reader_cache_hits_total", 1.0),
+            ("kvs_reader_cache_misses_total", 1.0),
+            ("kvs_reader_cache_hit_ratio", 0.5),
+        ]
+    );
+    for (name, _) in samples {
+        let kind = if name.ends_with("_total") {
+            "counter"
+        } else {
+            "gauge"
+        };
+        assert!(output.contains(&format!("# TYPE {} {}\n", name, kind)));
+        assert!(output.contains(&format!("# HELP {} ", name)));
+    }
+    Ok(())
+}
+
+// Rotating past `max_generations` should collapse the generations on the next write.
+#[test]
+fn max_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let compactions = Arc::new(AtomicUsize::new(0));
+    let counter = compactions.clone();
+    let options = KvStoreOptions::new()
+        .max_generations(4)
+        .on_compaction_start(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+        assert!(store.generations().len() <= 4);
+        store.rotate()?;
+    }
+    assert!(compactions.load(Ordering::SeqCst) >= 5);
+    for key_id in 0..20 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// Values should be returned in the order of the requested keys.
+#[test]
+fn get_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.rotate()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    let keys = ["key3", "missing", "key1", "key3", "key2"]
+        .iter()
+        .map(|key| key.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        store.get_many(&keys)?,
+        vec![
+            Some("value3".to_owned()),
+            None,
+            Some("value1".to_owned()),
+            Some("value3".to_owned()),
+            Some("value2".to_owned()),
+        ]
+    );
+    assert!(store.get_many(&[])?.is_empty());
+    Ok(())
+}
+
+// Compaction should work with the default and an overridden scratch directory.
+#[test]
+fn compaction_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store_dir = temp_dir.path().join("store");
+    let scratch_dir = temp_dir.path().join("scratch");
+    let leftovers = |dir: &std::path::Path| {
+        WalkDir::new(dir)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("compacting".as_ref()))
+            .count()
+    };
+
+    for options in [
+        KvStoreOptions::new(),
+        KvStoreOptions::new().compaction_dir(&scratch_dir),
+    ] {
+        let mut store = KvStore::open_with_options(&store_dir, options)?;
+        for iter in 0..3 {
+            for key_id in 0..100 {
+                store.set(format!("key{}", key_id), format!("value{}", iter))?;
+            }
+        }
+        assert!(store.compact()? > 0);
+        drop(store);
+        assert_eq!(leftovers(&store_dir), 0);
+        assert_eq!(leftovers(&scratch_dir), 0);
+
+        let mut store = KvStore::open(&store_dir)?;
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some("value2".to_owned())
+            );
+        }
+    }
+    assert!(scratch_dir.is_dir());
+    Ok(())
+}
+
+// Two log files parsing to the same generation should be rejected at open.
+#[test]
+fn duplicate_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    std::fs::copy(
+        temp_dir.path().join("1.log"),
+        temp_dir.path().join("01.log"),
+    )?;
+
+    assert_eq!(
+        KvStore::open(temp_dir.path()).err(),
+        Some(KvsError::DuplicateGeneration { gen: 1 })
+    );
+    assert_eq!(
+        KvStore::open_read_only(temp_dir.path()).err(),
+        Some(KvsError::DuplicateGeneration { gen: 1 })
+    );
+    Ok(())
+}
+
+// Keys differing only in case should refer to the same entry when enabled.
+#[test]
+fn case_insensitive_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().case_insensitive(true);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("Foo".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("foo".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("FOO".to_owned())?, Some("value1".to_owned()));
+    store.set("BAR".to_owned(), "value2".to_owned())?;
+    store.remove("bar".to_owned())?;
+    assert_eq!(store.get("Bar".to_owned())?, None);
+    store.set("Baz".to_owned(), "value3".to_owned())?;
+
+    // listing keeps the form the key was set with
+    assert_eq!(
+        store.range("a", "z", None)?,
+        vec![
+            ("Baz".to_owned(), "value3".to_owned()),
+            ("Foo".to_owned(), "value1".to_owned()),
+        ]
+    );
+    assert_eq!(store.scan_pattern("f*", None)?, vec!["Foo"]);
+    assert_eq!(store.scan_pattern("*", None)?, vec!["Baz", "Foo"]);
+    drop(store);
+
+    // the setting is taken from the manifest on reopen
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("fOO".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("bar".to_owned())?, None);
+    store.compact()?;
+    assert_eq!(store.get("baz".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("Foo".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("foo".to_owned())?, None);
+    Ok(())
+}
+
+// A generation should be read back command by command with its positions.
+#[test]
+fn generation_reader() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let set = r#"{"Set":{"key":"key1","value":"value1"}}"#;
+    let remove = r#"{"Remove":{"key":"key1"}}"#;
+    std::fs::write(
+        temp_dir.path().join("3.log"),
+        format!("{}{}{}{{\"Set\":", set, remove, set),
+    )?;
+
+    let reader = GenerationReader::open(temp_dir.path(), 3)?;
+    assert_eq!(reader.gen(), 3);
+    let entries = reader.collect::<Result<Vec<_>>>()?;
+    let (set_len, remove_len) = (set.len() as u64, remove.len() as u64);
+    assert_eq!(
+        entries,
+        vec![
+            (
+                CommandPos {
+                    gen: 3,
+                    pos: 0,
+                    len: set_len
+                },
+                LogCommand::Set {
+                    key: "key1".to_owned(),
+                    value: "value1".to_owned(),
+                    version: None,
+                    modified: None
+                }
+            ),
+            (
+                CommandPos {
+                    gen: 3,
+                    pos: set_len,
+                    len: remove_len
+                },
+                LogCommand::Remove {
+                    key: "key1".to_owned()
+                }
+            ),
+            (
+                CommandPos {
+                    gen: 3,
+                    pos: set_len + remove_len,
+                    len: set_len
+                },
+                LogCommand::Set {
+                    key: "key1".to_owned(),
+                    value: "value1".to_owned(),
+                    version: None,
+                    modified: None
+                }
+            ),
+        ]
+    );
+    Ok(())
+}
+
+// Swapping should exchange both values or, after a crash, neither.
+#[test]
+fn swap_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let before = std::fs::metadata(temp_dir.path().join("1.log"))?.len();
+    store.swap("key1".to_owned(), "key2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        store.swap("key1".to_owned(), "missing".to_owned()),
+        Err(KvsError::KeyNotFound)
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    // a crash after the first of the new entries keeps the old values
+    let log = std::fs::read(temp_dir.path().join("1.log"))?;
+    let first_set = r#"{"BatchStart":{"len":2}}{"Set":{"key":"key1","value":"value2"}}"#;
+    assert!(log[before as usize..].starts_with(first_set.as_bytes()));
+    let crash_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        crash_dir.path().join("1.log"),
+        &log[..before as usize + first_set.len()],
+    )?;
+    let mut store = KvStore::open(crash_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Live bytes should follow the live entries and ignore stale ones.
+#[test]
+fn live_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.live_bytes(), 0);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let entry_len = store.live_bytes();
+    assert_eq!(
+        entry_len,
+        r#"{"Set":{"key":"key1","value":"value1"}}"#.len() as u64
+    );
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.live_bytes(), 2 * entry_len);
+
+    // overwriting and compacting leave the live size alone
+    for _ in 0..10 {
+        store.set("key1".to_owned(), "value3".to_owned())?;
+    }
+    assert_eq!(store.live_bytes(), 2 * entry_len);
+    store.compact()?;
+    assert_eq!(store.live_bytes(), 2 * entry_len);
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.live_bytes(), entry_len);
+    Ok(())
+}
+
+// Replaying the logs on open should report monotonically growing progress.
+#[test]
+fn replay_progress() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    store.rotate()?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    drop(store);
+
+    let reports = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let sink = reports.clone();
+    let options = KvStoreOptions::new()
+        .on_replay_progress(10, move |progress| sink.lock().unwrap().push(progress));
+    KvStore::open_with_options(temp_dir.path(), options)?;
+
+    let reports = reports.lock().unwrap();
+    // every 10 records plus once after each of the two generations
+    assert_eq!(reports.len(), 15 + 2);
+    assert!(reports
+        .windows(2)
+        .all(|pair| pair[0].records <= pair[1].records && pair[0].bytes <= pair[1].bytes));
+    let last = reports.last().unwrap();
+    assert_eq!(last.records, 150);
+    assert_eq!(last.gen, 2);
+    assert_eq!(
+        last.bytes,
+        std::fs::metadata(temp_dir.path().join("1.log"))?.len()
+            + std::fs::metadata(temp_dir.path().join("2.log"))?.len()
+    );
+    Ok(())
+}
+
+// `kvs --compaction-threshold` should keep stale data below the threshold on disk.
+#[test]
+fn cli_compaction_threshold() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = "x".repeat(100 * 1024);
+    for _ in 0..15 {
+        Command::cargo_bin("kvs_2")
+            .unwrap()
+            .args(["--compaction-threshold", "100000000", "set", "key1", &value])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    let log_size = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+        .map(|entry| entry.metadata().unwrap().len())
+        .sum::<u64>();
+    assert!(log_size > 15 * 100 * 1024);
+
+    Command::cargo_bin("kvs_2")
+        .unwrap()
+        .args(["get", "key1", "--compaction-threshold", "1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(value.as_str()).trim());
+
+    for threshold in ["0", "-1", "big"] {
+        Command::cargo_bin("kvs_2")
+            .unwrap()
+            .args(["--compaction-threshold", threshold, "get", "key1"])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure();
+    }
+}
+
+// Clearing should drop every key and leave a single fresh generation.
+#[test]
+fn clear_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    store.rotate()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.len(), 100);
+
+    store.clear()?;
+    assert_eq!(store.len(), 0);
+    assert!(store.is_empty());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    let logs = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .collect::<Vec<_>>();
+    assert_eq!(logs, vec![temp_dir.path().join("3.log")]);
+    // Only the file header is left.
+    assert_eq!(
+        std::fs::read_to_string(&logs[0])?,
+        r#"{"kvs_log":1,"flags":0}"#
+    );
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should remove the key only when the value matches.
+#[test]
+fn remove_if_value_matches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let log_len = || {
+        std::fs::metadata(temp_dir.path().join("1.log"))
+            .unwrap()
+            .len()
+    };
+    let len = log_len();
+
+    assert!(!store.remove_if("key1", "value2")?);
+    assert!(!store.remove_if("key2", "value1")?);
+    assert_eq!(log_len(), len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    assert!(store.remove_if("key1", "value1")?);
+    assert!(log_len() > len);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// A clock that moves forward by a second every time it is read.
+struct TickingClock(MockClock);
+
+impl Clock for TickingClock {
+    fn now(&self) -> SystemTime {
+        let now = self.0.now();
+        self.0.advance(Duration::from_secs(1));
+        now
+    }
+}
+
+// Compaction timing should follow the injected clock.
+#[test]
+fn mock_clock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = MockClock::default();
+    let durations = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let options = {
+        let durations = durations.clone();
+        KvStoreOptions::new()
+            .clock(TickingClock(clock.clone()))
+            .on_compaction_end(move |info| durations.lock().unwrap().push(info.duration))
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let before = clock.now();
+    store.compact()?;
+    assert_eq!(*durations.lock().unwrap(), vec![Duration::from_secs(1)]);
+    // read once at the start and once at the end of the compaction
+    assert_eq!(clock.now(), before + Duration::from_secs(2));
+    Ok(())
+}
+
+// A store on an in-memory file provider should work without touching the disk.
+#[test]
+fn memory_file_provider() -> Result<()> {
+    let files = MemoryFileProvider::new();
+    let path = std::env::temp_dir().join("kvs-memory-file-provider");
+    let options = || KvStoreOptions::new().file_provider(files.clone());
+    let mut store = KvStore::open_with_options(&path, options())?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    store.compact()?;
+    store.rotate()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open_with_options(&path, options())?;
+    assert_eq!(store.len(), 99);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key99".to_owned())?, Some("9".to_owned()));
+    assert!(!path.exists());
+    Ok(())
+}
+
+// Restoring, receiving, raw scans and frozen stores should go through the file provider too.
+#[test]
+fn memory_file_provider_tools() -> Result<()> {
+    let files = MemoryFileProvider::new();
+    let root = std::env::temp_dir().join("kvs-memory-file-provider-tools");
+    let options = || KvStoreOptions::new().file_provider(files.clone());
+    let mut store = KvStore::open_with_options(root.join("store"), options())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.freeze(&root.join("frozen"))?;
+    let mut stream = Vec::new();
+    store.stream_log(0, &mut stream)?;
+    let gen = *store.generations().last().unwrap();
+    drop(store);
+
+    let mut frozen = FrozenKvStore::open_with_options(&root.join("frozen"), options())?;
+    assert_eq!(frozen.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(
+        GenerationReader::open_with_options(&root.join("store"), gen, options())?.count(),
+        2
+    );
+    assert_eq!(
+        scan_raw_with_options(&root.join("store"), options())?.count(),
+        2
+    );
+
+    KvStore::receive_log_with_options(root.join("replica"), &stream[..], options())?;
+    KvStore::restore_with_options(&root.join("store"), &root.join("restored"), options())?;
+    for dir in ["replica", "restored"] {
+        let mut copy = KvStore::open_with_options(root.join(dir), options())?;
+        assert_eq!(copy.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+    assert!(!root.exists());
+    Ok(())
+}
+
+// A sharded store should spread keys over its shards and behave like a single store.
+#[test]
+fn sharded_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = ShardedKvStore::open(temp_dir.path(), 4)?;
+    exercise_engine(&mut store)?;
+
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("updated{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    assert_eq!(store.len(), 999);
+    for shard in store.shards() {
+        assert!(shard.len() > 100);
+    }
+    assert!(store.compact()? > 0);
+
+    let mut keys = (1..1000)
+        .map(|key_id| format!("key{}", key_id))
+        .collect::<Vec<_>>();
+    keys.sort();
+    assert_eq!(store.keys(), keys);
+    drop(store);
+
+    assert_eq!(
+        ShardedKvStore::open(temp_dir.path(), 3).err(),
+        Some(KvsError::ConfigMismatch {
+            setting: "shards".to_owned()
+        })
+    );
+    let mut store = ShardedKvStore::open(temp_dir.path(), 4)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("updated{}", key_id))
+        );
+    }
+    Ok(())
+}
+
+// Write amplification should count log records and compaction copies per byte set.
+#[test]
+fn write_amplification() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.write_amplification(), 0.0);
+
+    // `{"Set":{"key":"key1","value":"value1"}}` for 10 bytes of key and value
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.write_amplification(), 39.0 / 10.0);
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.write_amplification(), 78.0 / 20.0);
+    // the live entry is copied once more
+    store.compact()?;
+    assert_eq!(store.write_amplification(), 117.0 / 20.0);
+    Ok(())
+}
+
+// Only the entries with a matching value should be returned, in key order.
+#[test]
+fn filter_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..20 {
+        store.set(format!("key{:02}", key_id), format!("{}", key_id * 7))?;
+    }
+    store.set("key03".to_owned(), "1000".to_owned())?;
+    store.remove("key10".to_owned())?;
+
+    let even = store.filter_values(|value| value.parse::<u32>().unwrap() % 2 == 0)?;
+    let expected = [0, 2, 3, 4, 6, 8, 12, 14, 16, 18]
+        .iter()
+        .map(|&key_id| {
+            let value = if key_id == 3 { 1000 } else { key_id * 7 };
+            (format!("key{:02}", key_id), value.to_string())
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(even, expected);
+    assert!(store.filter_values(|_| false)?.is_empty());
+    assert_eq!(
+        store.filter_values(|_| true)?,
+        store.iter().collect::<Result<Vec<_>>>()?
+    );
+    Ok(())
+}
+
+// Structs should round-trip through their JSON form.
+#[test]
+fn typed_values() -> Result<()> {
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Point {
+        x: i32,
+        y: i32,
+        label: String,
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let point = Point {
+        x: 3,
+        y: -4,
+        label: "origin".to_owned(),
+    };
+    store.set_as("point".to_owned(), &point)?;
+    assert_eq!(store.get_as::<Point>("point")?, Some(point));
+    assert_eq!(store.get_as::<Point>("missing")?, None);
+
+    store.set("plain".to_owned(), "not json".to_owned())?;
+    assert!(matches!(
+        store.get_as::<Point>("plain"),
+        Err(KvsError::SerdeError(_))
+    ));
+    Ok(())
+}
+
+// A content-addressed compaction should write each distinct value only once.
+#[test]
+fn content_addressed_compaction() -> Result<()> {
+    let value = |key_id: usize| format!("{:0>200}", key_id % 3);
+    let compacted_size = |options: KvStoreOptions| -> Result<(TempDir, u64)> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), value(key_id))?;
+        }
+        store.compact()?;
+        let gen = *store
+            .generations()
+            .first()
+            .expect("no compacted generation");
+        let size = std::fs::metadata(temp_dir.path().join(format!("{}.log", gen)))?.len();
+        Ok((temp_dir, size))
+    };
+    let (_, plain_size) = compacted_size(KvStoreOptions::new())?;
+    let (temp_dir, deduped_size) =
+        compacted_size(KvStoreOptions::new().content_addressed_compaction(true))?;
+    assert!(deduped_size * 4 < plain_size);
+
+    let mut store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions::new().content_addressed_compaction(true),
+    )?;
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value(key_id)));
+    }
+    let gen = store.generations()[0];
+    assert_eq!(store.get_at_gen("key4", gen)?, Some(value(4)));
+    store.set("key4".to_owned(), "updated".to_owned())?;
+    store.remove("key5".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    // a plain compaction turns the references back into values
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.compact()?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key4".to_owned())?, Some("updated".to_owned()));
+    assert_eq!(store.get("key5".to_owned())?, None);
+    assert_eq!(store.get("key999".to_owned())?, Some(value(999)));
+    assert_eq!(store.len(), 999);
+    Ok(())
+}
+
+// An exported index should open a copy of the logs without replaying them.
+#[test]
+fn export_import_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.compact()?;
+    store.set("key1".to_owned(), "updated".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let mut index = Vec::new();
+    store.export_index(&mut index)?;
+
+    let copy_logs = || -> Result<TempDir> {
+        let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+        for entry in std::fs::read_dir(temp_dir.path())? {
+            let path = entry?.path();
+            std::fs::copy(&path, replica_dir.path().join(path.file_name().unwrap()))?;
+        }
+        Ok(replica_dir)
+    };
+    let replica_dir = copy_logs()?;
+    let replayed = Arc::new(AtomicU64::new(0));
+    let options = {
+        let replayed = replayed.clone();
+        KvStoreOptions::new().on_replay_progress(1, move |progress| {
+            replayed.store(progress.records, Ordering::SeqCst);
+        })
+    };
+    let mut replica = KvStore::import_index(replica_dir.path(), options, index.as_slice())?;
+    assert_eq!(replayed.load(Ordering::SeqCst), 0);
+    assert_eq!(replica.len(), 99);
+    assert_eq!(replica.get("key1".to_owned())?, Some("updated".to_owned()));
+    assert_eq!(replica.get("key2".to_owned())?, None);
+    assert_eq!(replica.get("key99".to_owned())?, Some("value99".to_owned()));
+    replica.set("key100".to_owned(), "value100".to_owned())?;
+    drop(replica);
+    let mut replica = KvStore::open(replica_dir.path())?;
+    assert_eq!(
+        replica.get("key100".to_owned())?,
+        Some("value100".to_owned())
+    );
+
+    // the logs changed since the export
+    let replica_dir = copy_logs()?;
+    std::fs::write(replica_dir.path().join("99.log"), "")?;
+    assert!(matches!(
+        KvStore::import_index(replica_dir.path(), KvStoreOptions::new(), index.as_slice()),
+        Err(KvsError::InvalidIndex { .. })
+    ));
+    let replica_dir = copy_logs()?;
+    let last_gen = *store.generations().last().unwrap();
+    std::fs::write(replica_dir.path().join(format!("{}.log", last_gen)), "")?;
+    assert!(matches!(
+        KvStore::import_index(replica_dir.path(), KvStoreOptions::new(), index.as_slice()),
+        Err(KvsError::InvalidIndex { .. })
+    ));
+    Ok(())
+}
+
+// A removed key should stay removed whether compaction runs before or after reopening.
+#[test]
+fn removed_key_stays_removed() -> Result<()> {
+    let strategies = [
+        CompactionStrategy::Full,
+        CompactionStrategy::Minor { max_size: u64::MAX },
+        CompactionStrategy::Minor { max_size: 100 },
+    ];
+    for strategy in strategies {
+        let options = || KvStoreOptions::new().compaction_strategy(strategy);
+        for compact_before_reopen in [true, false] {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+            // large enough that minor compactions with a small limit leave this generation
+            // alone, so only the one with the tombstone is rewritten
+            store.set("key1".to_owned(), "x".repeat(200))?;
+            store.set("key2".to_owned(), "value2".to_owned())?;
+            store.rotate()?;
+            store.remove("key1".to_owned())?;
+            if compact_before_reopen {
+                store.compact()?;
+            }
+            drop(store);
+
+            let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+            assert_eq!(store.get("key1".to_owned())?, None);
+            if !compact_before_reopen {
+                store.compact()?;
+                assert_eq!(store.get("key1".to_owned())?, None);
+            }
+            store.compact()?;
+            drop(store);
+
+            let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+            assert_eq!(
+                store.get("key1".to_owned())?,
+                None,
+                "{:?}, compact before reopen: {}",
+                strategy,
+                compact_before_reopen
+            );
+            assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+            assert_eq!(store.len(), 1);
+        }
+    }
+    Ok(())
+}
+
+// The located record should hold the key's current value.
+#[test]
+fn locate_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.rotate()?;
+    store.set("key1".to_owned(), "updated".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.locate("key2"), None);
+    assert_eq!(store.locate("missing"), None);
+
+    let (gen, pos, len) = store.locate("key1").expect("key1 not indexed");
+    assert_eq!(gen, 2);
+    let log = std::fs::read(temp_dir.path().join(format!("{}.log", gen)))?;
+    let record = &log[pos as usize..(pos + len) as usize];
+    assert_eq!(
+        serde_json::from_slice::<LogCommand>(record)?,
+        LogCommand::Set {
+            key: "key1".to_owned(),
+            value: "updated".to_owned(),
+            version: None,
+            modified: None
+        }
+    );
+    Ok(())
+}
+
+// A frozen store should answer every lookup like the live store it was frozen from.
+#[test]
+fn freeze_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path().join("live"))?;
+    for key_id in 0..500 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.set("key7".to_owned(), "updated".to_owned())?;
+    store.set("ключ".to_owned(), "значение".to_owned())?;
+    store.remove("key8".to_owned())?;
+    let frozen_path = temp_dir.path().join("frozen.kvs");
+    store.freeze(&frozen_path)?;
+
+    let mut frozen = FrozenKvStore::open(&frozen_path)?;
+    assert_eq!(frozen.len(), store.len());
+    for key_id in 0..510 {
+        let key = format!("key{}", key_id);
+        assert_eq!(frozen.get(&key)?, store.get(key)?);
+    }
+    assert_eq!(frozen.get("ключ")?, Some("значение".to_owned()));
+    assert_eq!(frozen.get("")?, None);
+    assert_eq!(frozen.get("zzz")?, None);
+
+    let empty_path = temp_dir.path().join("empty.kvs");
+    KvStore::open(temp_dir.path().join("empty"))?.freeze(&empty_path)?;
+    let mut empty = FrozenKvStore::open(&empty_path)?;
+    assert!(empty.is_empty());
+    assert_eq!(empty.get("key1")?, None);
+
+    std::fs::write(&empty_path, "not a frozen store, just some text")?;
+    assert!(matches!(
+        FrozenKvStore::open(&empty_path),
+        Err(KvsError::InvalidFrozen { .. })
+    ));
+    Ok(())
+}
+
+// Idle compaction should wait for a pause in the writes.
+#[test]
+fn idle_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = MockClock::default();
+    let compactions = Arc::new(AtomicUsize::new(0));
+    let options = {
+        let compactions = compactions.clone();
+        KvStoreOptions::new()
+            .auto_compact(false)
+            .clock(clock.clone())
+            .on_compaction_end(move |_| {
+                compactions.fetch_add(1, Ordering::SeqCst);
+            })
+    };
+    let store = Arc::new(std::sync::Mutex::new(KvStore::open_with_options(
+        temp_dir.path(),
+        options,
+    )?));
+    let compactor = IdleCompactor::spawn(store.clone(), Duration::from_secs(10));
+
+    for iter in 0..20 {
+        store
+            .lock()
+            .unwrap()
+            .set("key1".to_owned(), format!("value{}", iter))?;
+        clock.advance(Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(compactions.load(Ordering::SeqCst), 0);
+
+    clock.advance(Duration::from_secs(5));
+    let start = Instant::now();
+    while compactions.load(Ordering::SeqCst) == 0 {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "no idle compaction"
+        );
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    drop(compactor);
+    assert_eq!(compactions.load(Ordering::SeqCst), 1);
+    let mut store = store.lock().unwrap();
+    assert_eq!(store.get("key1".to_owned())?, Some("value19".to_owned()));
+    Ok(())
+}
+
+// An engine registered by name should be opened through the registry.
+#[test]
+fn engine_registry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opened = Arc::new(AtomicUsize::new(0));
+    {
+        let opened = opened.clone();
+        register_engine("test-memory", move |_| {
+            opened.fetch_add(1, Ordering::SeqCst);
+            Ok(Box::new(MemoryStore::new()))
+        });
+    }
+    let mut engine = open_engine("test-memory", temp_dir.path())?;
+    assert_eq!(opened.load(Ordering::SeqCst), 1);
+    exercise_engine(&mut *engine)?;
+    assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 0);
+
+    let mut engine = open_engine("kvs", temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    drop(engine);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    assert_eq!(
+        open_engine("missing", temp_dir.path()).err(),
+        Some(KvsError::UnknownEngine("missing".to_owned()))
+    );
+    Ok(())
+}
+
+// The CLI should open the engine named by `--engine`.
+#[test]
+fn cli_engine() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs_2")
+        .unwrap()
+        .args(["--engine", "kvs", "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs_2")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    Command::cargo_bin("kvs_2")
+        .unwrap()
+        .args(["get", "key1", "--engine", "missing"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("missing"));
+}
+
+// Adding a shard should only move the keys the new shard takes over.
+#[test]
+fn hash_ring_rebalance() {
+    let keys = (0..10000)
+        .map(|key_id| format!("key{}", key_id))
+        .collect::<Vec<_>>();
+    for shards in [1, 4, 8] {
+        let before = HashRing::new(shards, 128);
+        let after = HashRing::new(shards + 1, 128);
+        assert_eq!(after.points().count(), (shards + 1) * 128);
+        let mut moved = 0;
+        for key in &keys {
+            let (old, new) = (before.shard_of(key), after.shard_of(key));
+            if old != new {
+                assert_eq!(new, shards, "key moved between existing shards");
+                moved += 1;
+            }
+        }
+        let expected = keys.len() / (shards + 1);
+        assert!(
+            moved > expected / 2 && moved < expected * 3 / 2,
+            "{} of {} keys moved going to {} shards",
+            moved,
+            keys.len(),
+            shards + 1
+        );
+    }
+}
+
+// Merging overlapping stores should apply the conflict policy to the shared keys.
+#[test]
+fn merge_from() -> Result<()> {
+    let open_pair = || -> Result<(TempDir, KvStore, TempDir, KvStore)> {
+        let dir = TempDir::new().expect("unable to create temporary working directory");
+        let other_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(dir.path())?;
+        let mut other = KvStore::open(other_dir.path())?;
+        store.set("a".to_owned(), "mine".to_owned())?;
+        store.set("b".to_owned(), "mine".to_owned())?;
+        other.set("b".to_owned(), "theirs".to_owned())?;
+        other.set("c".to_owned(), "theirs".to_owned())?;
+        other.set("d".to_owned(), "theirs".to_owned())?;
+        other.remove("d".to_owned())?;
+        Ok((dir, store, other_dir, other))
+    };
+    let policies = vec![
+        (ConflictPolicy::KeepExisting, 1, "mine".to_owned()),
+        (ConflictPolicy::Overwrite, 2, "theirs".to_owned()),
+        (
+            ConflictPolicy::Resolve(Box::new(|key, existing, incoming| {
+                format!("{}:{}+{}", key, existing, incoming)
+            })),
+            2,
+            "b:mine+theirs".to_owned(),
+        ),
+    ];
+    for (policy, merged, b) in policies {
+        let (dir, mut store, _other_dir, mut other) = open_pair()?;
+        assert_eq!(store.merge_from(&mut other, policy)?, merged);
+        drop(store);
+        let mut store = KvStore::open(dir.path())?;
+        assert_eq!(store.get("a".to_owned())?, Some("mine".to_owned()));
+        assert_eq!(store.get("b".to_owned())?, Some(b));
+        assert_eq!(store.get("c".to_owned())?, Some("theirs".to_owned()));
+        assert_eq!(store.get("d".to_owned())?, None);
+        assert_eq!(other.len(), 2);
+    }
+    Ok(())
+}
+
+// A CAS against a stale version should fail and keep the newer value.
+#[test]
+fn versioned_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let v1 = store.set_versioned("key1".to_owned(), "value1".to_owned())?;
+    let v2 = store.set_versioned("key2".to_owned(), "value2".to_owned())?;
+    assert!(v2 > v1);
+    assert_eq!(
+        store.get_versioned("key1".to_owned())?,
+        Some(("value1".to_owned(), v1))
+    );
+
+    assert!(store.cas_version("key1".to_owned(), v1, "value3".to_owned())?);
+    let (_, v3) = store.get_versioned("key1".to_owned())?.unwrap();
+    assert!(v3 > v2);
+    assert!(!store.cas_version("key1".to_owned(), v1, "stale".to_owned())?);
+    assert!(!store.cas_version("key3".to_owned(), 0, "absent".to_owned())?);
+
+    store.set("key2".to_owned(), "plain".to_owned())?;
+    assert_eq!(
+        store.get_versioned("key2".to_owned())?,
+        Some(("plain".to_owned(), 0))
+    );
+
+    // Versions survive a compaction and keep increasing after reopening.
+    store.compact()?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_versioned("key1".to_owned())?,
+        Some(("value3".to_owned(), v3))
+    );
+    assert!(!store.cas_version("key1".to_owned(), v1, "stale".to_owned())?);
+    assert!(store.set_versioned("key1".to_owned(), "value4".to_owned())? > v3);
+    Ok(())
+}
+
+// A version overwritten by a plain set should not be handed out again after the
+// compaction dropping it, nor after clearing the store.
+#[test]
+fn versions_not_reused() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.set_versioned("key1".to_owned(), "value1".to_owned())?,
+        1
+    );
+    assert_eq!(
+        store.set_versioned("key1".to_owned(), "value2".to_owned())?,
+        2
+    );
+    store.set("key1".to_owned(), "plain".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.set_versioned("key1".to_owned(), "value3".to_owned())?,
+        3
+    );
+    store.clear()?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.set_versioned("key1".to_owned(), "value4".to_owned())?,
+        4
+    );
+    Ok(())
+}
+
+// A corrupt value should fail `get` by default and read as `None` under `SkipAsNone`.
+#[test]
+fn on_read_error() -> Result<()> {
+    for policy in [OnReadError::Fail, OnReadError::SkipAsNone] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().on_read_error(policy);
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+
+        let (gen, pos, _) = store.locate("key1").unwrap();
+        let path = temp_dir.path().join(format!("{}.log", gen));
+        let mut log = std::fs::read(&path)?;
+        log[pos as usize] = b'x';
+        std::fs::write(&path, log)?;
+
+        match policy {
+            OnReadError::Fail => assert!(store.get("key1".to_owned()).is_err()),
+            OnReadError::SkipAsNone => assert_eq!(store.get("key1".to_owned())?, None),
+        }
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+    Ok(())
+}
+
+// Bulk-loaded entries should all be found after reopening the store.
+#[test]
+fn bulk_load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let entries = (0..100_000).map(|i| (format!("key{}", i), format!("value{}", i)));
+    let mut store = KvStore::bulk_load(temp_dir.path(), entries)?;
+    assert_eq!(store.len(), 100_000);
+    store.set("key0".to_owned(), "updated".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 100_000);
+    assert_eq!(store.get("key0".to_owned())?, Some("updated".to_owned()));
+    for i in 1..100_000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    let entries = vec![("key".to_owned(), "value".to_owned())].into_iter();
+    assert_eq!(
+        KvStore::bulk_load(temp_dir.path(), entries).err(),
+        Some(KvsError::BulkLoadTargetNotEmpty)
+    );
+    Ok(())
+}
+
+// A newline-delimited log should hold one record per line and replay like any other.
+#[test]
+fn newline_delimited_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let options = KvStoreOptions::new().newline_delimited(true);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "line1\nline2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set_transactional(vec![("key3".to_owned(), "value3".to_owned())])?;
+    let gen = *store.generations().last().unwrap();
+    drop(store);
+
+    let log = std::fs::read_to_string(temp_dir.path().join(format!("{}.log", gen)))?;
+    let records = scan_raw(temp_dir.path())?
+        .filter(|record| matches!(record, Ok((g, _)) if *g == gen))
+        .count();
+    assert_eq!(records, 6);
+    // What `wc -l` reports.
+    assert_eq!(log.matches('\n').count(), records);
+    // Every line is JSON, the file header on the first one and a record on each other.
+    let lines = log.lines().collect::<Vec<_>>();
+    assert_eq!(lines[0], r#"{"kvs_log":1,"flags":2}"#);
+    for line in &lines {
+        serde_json::from_str::<serde_json::Value>(line)?;
+    }
+    for line in &lines[1..] {
+        serde_json::from_str::<LogCommand>(line)?;
+    }
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("line1\nline2".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    store.compact()?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("line1\nline2".to_owned())
+    );
+    Ok(())
+}
+
+// Only the keys last set within the window should be listed, also after reopening.
+#[test]
+fn keys_modified_between() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+    let clock = MockClock::new(start);
+    let options = || {
+        KvStoreOptions::new()
+            .clock(clock.clone())
+            .track_modified(true)
+    };
+    let at = |secs| start + Duration::from_secs(secs);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    clock.advance(Duration::from_secs(10));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.set_transactional(vec![("key4".to_owned(), "value4".to_owned())])?;
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    clock.advance(Duration::from_secs(10));
+    store.set("key1".to_owned(), "updated".to_owned())?;
+    store.rename("key5".to_owned(), "key6".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    assert_eq!(
+        store.keys_modified_between(at(0), at(10)),
+        Vec::<String>::new()
+    );
+    assert_eq!(
+        store.keys_modified_between(at(5), at(15)),
+        vec!["key3", "key4"]
+    );
+    assert_eq!(
+        store.keys_modified_between(at(20), at(21)),
+        vec!["key1", "key6"]
+    );
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(
+        store.keys_modified_between(at(5), at(15)),
+        vec!["key3", "key4"]
+    );
+    assert_eq!(
+        store.keys_modified_between(at(0), at(30)),
+        vec!["key1", "key3", "key4", "key6"]
+    );
+    Ok(())
+}
+
+// A handle should see the data again after refreshing past a compaction by another handle.
+#[test]
+fn refresh_after_external_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+
+    let mut other = KvStore::open(temp_dir.path())?;
+    other.set("key0".to_owned(), "other".to_owned())?;
+    other.remove("key1".to_owned())?;
+    other.compact()?;
+    drop(other);
+    assert_eq!(store.get("key0".to_owned())?, Some("9".to_owned()));
+
+    store.refresh()?;
+    assert_eq!(store.len(), 99);
+    assert_eq!(store.get("key0".to_owned())?, Some("other".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("9".to_owned()));
+
+    store.set("key1".to_owned(), "again".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("again".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("9".to_owned()));
+    Ok(())
+}
+
+// An empty value should read as `Some("")`, not as a removed key, also after compaction.
+#[test]
+fn empty_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set_empty("key2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    store.compact()?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.get("key1".to_owned())?, Some("".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+// A store rebuilt from the streamed log files should hold the same data.
+#[test]
+fn stream_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.rotate()?;
+    store.set("key1".to_owned(), "updated".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    let mut stream = Vec::new();
+    let last = store.stream_log(0, &mut stream)?;
+    assert_eq!(Some(&last), store.generations().last());
+    assert_eq!(KvStore::receive_log(replica_dir.path(), &stream[..])?, last);
+    for gen in store.generations() {
+        let name = format!("{}.log", gen);
+        assert_eq!(
+            std::fs::read(temp_dir.path().join(&name))?,
+            std::fs::read(replica_dir.path().join(&name))?
+        );
+    }
+    let mut replica = KvStore::open(replica_dir.path())?;
+    assert_eq!(replica.get("key1".to_owned())?, Some("updated".to_owned()));
+    assert_eq!(replica.get("key2".to_owned())?, None);
+
+    // Catch up from the last generation streamed.
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(replica);
+    let mut stream = Vec::new();
+    assert_eq!(store.stream_log(last, &mut stream)?, last);
+    assert_eq!(KvStore::receive_log(replica_dir.path(), &stream[..])?, last);
+    let mut replica = KvStore::open(replica_dir.path())?;
+    assert_eq!(replica.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // A cut-off stream is rejected.
+    assert!(KvStore::receive_log(replica_dir.path(), &stream[..stream.len() - 1]).is_err());
+
+    // Generations compacted away at the source are removed from the replica.
+    drop(replica);
+    store.remove("key3".to_owned())?;
+    store.compact()?;
+    let mut stream = Vec::new();
+    let caught_up = store.stream_log(last, &mut stream)?;
+    assert_eq!(
+        KvStore::receive_log(replica_dir.path(), &stream[..])?,
+        caught_up
+    );
+    let mut replica_files = std::fs::read_dir(replica_dir.path())?
+        .map(|entry| Ok(entry?.file_name().into_string().unwrap()))
+        .filter(|name| name.as_ref().map_or(true, |name| name.ends_with(".log")))
+        .collect::<Result<Vec<_>>>()?;
+    replica_files.sort();
+    let mut store_files = store
+        .generations()
+        .iter()
+        .map(|gen| format!("{}.log", gen))
+        .collect::<Vec<_>>();
+    store_files.sort();
+    assert_eq!(replica_files, store_files);
+    let mut replica = KvStore::open(replica_dir.path())?;
+    assert_eq!(replica.get("key1".to_owned())?, Some("updated".to_owned()));
+    assert_eq!(replica.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+// A replica should take over the settings of the streamed store.
+#[test]
+fn stream_log_manifest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().case_insensitive(true);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("Key1".to_owned(), "value1".to_owned())?;
+
+    let mut stream = Vec::new();
+    store.stream_log(0, &mut stream)?;
+    KvStore::receive_log(replica_dir.path(), &stream[..])?;
+    assert_eq!(
+        std::fs::read(temp_dir.path().join("MANIFEST"))?,
+        std::fs::read(replica_dir.path().join("MANIFEST"))?
+    );
+    let mut replica = KvStore::open(replica_dir.path())?;
+    assert_eq!(replica.get("KEY1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A limited scan should stop after `limit` entries without reading the values past it.
+#[test]
+fn scan_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{:04}", key_id), format!("value{}", key_id))?;
+    }
+    // Break the value of the 11th key, so reading it fails.
+    let (gen, pos, _) = store.locate("key0010").unwrap();
+    let path = temp_dir.path().join(format!("{}.log", gen));
+    let mut log = std::fs::read(&path)?;
+    log[pos as usize] = b'x';
+    std::fs::write(&path, log)?;
+
+    let entries = store.range("key", "kez", Some(10))?;
+    assert_eq!(entries.len(), 10);
+    assert_eq!(entries[9], ("key0009".to_owned(), "value9".to_owned()));
+    assert!(store.range("key", "kez", None).is_err());
+    assert!(store.range("key", "kez", Some(11)).is_err());
+
+    assert_eq!(store.scan_pattern("key*", Some(10))?.len(), 10);
+    assert_eq!(
+        store.scan_pattern("key*9", Some(3))?,
+        vec!["key0009", "key0019", "key0029"]
+    );
+    assert_eq!(store.scan_pattern("key*", Some(0))?.len(), 0);
+    assert_eq!(store.scan_pattern("key*", None)?.len(), 1000);
+    Ok(())
+}
+
+// Paging through a store should visit every key exactly once, in order.
+#[test]
+fn scan_from_pages() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..95 {
+        store.set(format!("key{:03}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key050".to_owned())?;
+
+    let mut visited = Vec::new();
+    let mut cursor = None;
+    let mut pages = 0;
+    loop {
+        let (entries, next) = store.scan_from(cursor, 10)?;
+        assert!(entries.len() <= 10);
+        visited.extend(entries);
+        pages += 1;
+        match next {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+    assert_eq!(pages, 10);
+    let expected = (0..95)
+        .filter(|&key_id| key_id != 50)
+        .map(|key_id| (format!("key{:03}", key_id), format!("value{}", key_id)))
+        .collect::<Vec<_>>();
+    assert_eq!(visited, expected);
+
+    // A cursor need not be an existing key.
+    let (entries, next) = store.scan_from(Some("key0505".to_owned()), 2)?;
+    assert_eq!(
+        entries,
+        vec![
+            ("key051".to_owned(), "value51".to_owned()),
+            ("key052".to_owned(), "value52".to_owned()),
+        ]
+    );
+    assert_eq!(next, Some("key053".to_owned()));
+    assert_eq!(store.scan_from(Some("key094".to_owned()), 1)?.1, None);
+    Ok(())
+}
+
+// Counts the syncs of the log files it opens and of directories, other operations go to the disk.
+#[derive(Clone, Default)]
+struct SyncCounter(Arc<AtomicUsize>);
+
+struct CountedFile(Box<dyn LogFile>, Arc<AtomicUsize>);
+
+impl std::io::Read for CountedFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl std::io::Write for CountedFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl std::io::Seek for CountedFile {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+impl LogFile for CountedFile {
+    fn set_len(&mut self, len: u64) -> std::io::Result<()> {
+        self.0.set_len(len)
+    }
+
+    fn sync_all(&mut self) -> std::io::Result<()> {
+        self.1.fetch_add(1, Ordering::SeqCst);
+        self.0.sync_all()
+    }
+}
+
+impl SyncCounter {
+    fn wrap(&self, file: std::io::Result<Box<dyn LogFile>>) -> std::io::Result<Box<dyn LogFile>> {
+        Ok(Box::new(CountedFile(file?, self.0.clone())))
+    }
+}
+
+impl FileProvider for SyncCounter {
+    fn open(&self, path: &Path) -> std::io::Result<Box<dyn LogFile>> {
+        self.wrap(StdFileProvider.open(path))
+    }
+
+    fn append(&self, path: &Path) -> std::io::Result<Box<dyn LogFile>> {
+        self.wrap(StdFileProvider.append(path))
+    }
+
+    fn create(&self, path: &Path) -> std::io::Result<Box<dyn LogFile>> {
+        self.wrap(StdFileProvider.create(path))
+    }
+
+    fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+        StdFileProvider.read(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> std::io::Result<()> {
+        StdFileProvider.write(path, contents)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        StdFileProvider.rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> std::io::Result<()> {
+        StdFileProvider.remove(path)
+    }
+
+    fn len(&self, path: &Path) -> std::io::Result<u64> {
+        StdFileProvider.len(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        StdFileProvider.exists(path)
+    }
+
+    fn list(&self, dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+        StdFileProvider.list(dir)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> std::io::Result<()> {
+        StdFileProvider.create_dir_all(dir)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> std::io::Result<()> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        StdFileProvider.sync_dir(dir)
+    }
+}
+
+// Every write should be synced under `SyncPolicy::EveryWrite` and none by default.
+#[test]
+fn sync_every_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let counter = SyncCounter::default();
+    let options = KvStoreOptions::new().file_provider(counter.clone());
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact()?;
+    assert_eq!(counter.0.load(Ordering::SeqCst), 0);
+    drop(store);
+
+    let options = KvStoreOptions::new()
+        .file_provider(counter.clone())
+        .sync_policy(SyncPolicy::EveryWrite);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set_transactional(vec![("key2".to_owned(), "value2".to_owned())])?;
+    store.remove("key2".to_owned())?;
+    // The three writes and the directory the new log was created in.
+    assert_eq!(counter.0.load(Ordering::SeqCst), 4);
+    // The compacted log, the directory it was renamed into and the directory again
+    // for the log the writes continue in.
+    store.compact()?;
+    assert_eq!(counter.0.load(Ordering::SeqCst), 7);
+    drop(store);
+
+    // The cli syncs its writes, so they are on disk once it exits.
+    Command::cargo_bin("kvs_2")
+        .unwrap()
+        .args(["set", "key3", "value3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Writes made while a shared compaction copies entries should all survive it.
+#[test]
+fn compact_shared_concurrent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_rate_limit(200 * 1024);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "x".repeat(1024))?;
+        store.set(format!("key{}", key_id), "y".repeat(1024))?;
+    }
+    let store = Arc::new(Mutex::new(store));
+
+    let done = Arc::new(AtomicUsize::new(0));
+    let writes = Arc::new(AtomicU64::new(0));
+    let writer = {
+        let (store, done, writes) = (store.clone(), done.clone(), writes.clone());
+        std::thread::spawn(move || -> Result<Vec<(String, String)>> {
+            let mut model = Vec::new();
+            while done.load(Ordering::SeqCst) == 0 {
+                let write = writes.load(Ordering::SeqCst);
+                let (key, value) = (format!("key{}", write % 150), format!("value{}", write));
+                store.lock().unwrap().set(key.clone(), value.clone())?;
+                model.push((key, value));
+                writes.fetch_add(1, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            Ok(model)
+        })
+    };
+    std::thread::sleep(Duration::from_millis(20));
+    let before = writes.load(Ordering::SeqCst);
+    KvStore::compact_shared(&store)?;
+    let after = writes.load(Ordering::SeqCst);
+    done.store(1, Ordering::SeqCst);
+    let model = writer.join().unwrap()?;
+    assert!(after > before);
+
+    let mut expected = std::collections::BTreeMap::new();
+    for key_id in 0..100 {
+        expected.insert(format!("key{}", key_id), "y".repeat(1024));
+    }
+    expected.extend(model);
+    let mut store = Arc::try_unwrap(store).ok().unwrap().into_inner().unwrap();
+    for (key, value) in &expected {
+        assert_eq!(store.get(key.clone())?, Some(value.clone()));
+    }
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for (key, value) in &expected {
+        assert_eq!(store.get(key.clone())?, Some(value.clone()));
+    }
+    Ok(())
+}
+
+// The estimated memory usage should grow linearly with the number of keys.
+#[test]
+fn memory_usage_grows_with_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let empty = store.memory_usage();
+    assert_eq!(empty.index, 0);
+
+    let mut usages = Vec::new();
+    for round in 0..3 {
+        for key_id in 0..1000 {
+            store.set(
+                format!("key{:06}", round * 1000 + key_id),
+                "value".to_owned(),
+            )?;
+        }
+        usages.push(store.memory_usage());
+    }
+    let first = usages[0].index;
+    assert!(first >= 1000 * 9);
+    assert_eq!(usages[1].index, 2 * first);
+    assert_eq!(usages[2].index, 3 * first);
+    assert!(usages[2].total() > usages[0].total());
+    assert_eq!(usages[2].versions, 0);
+    assert_eq!(usages[2].modified, 0);
+    assert!(usages[2].readers > 0);
+
+    Ok(())
+}
+
+// A secondary index on a JSON field should list the keys holding each field value.
+#[test]
+fn secondary_index_json_field() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let user = |city: &str| format!(r#"{{"city":"{}"}}"#, city);
+    store.set("alice".to_owned(), user("paris"))?;
+    store.set("bob".to_owned(), user("berlin"))?;
+    store.set("carol".to_owned(), "not json".to_owned())?;
+
+    let city = |value: &str| {
+        let value = serde_json::from_str::<serde_json::Value>(value).ok()?;
+        Some(value.get("city")?.as_str()?.to_owned())
+    };
+    store.create_secondary_index("city", city)?;
+    assert_eq!(store.query_secondary("city", "paris")?, vec!["alice"]);
+    assert!(store.query_secondary("city", "rome")?.is_empty());
+    assert_eq!(
+        store.query_secondary("age", "30"),
+        Err(KvsError::UnknownSecondaryIndex("age".to_owned()))
+    );
+
+    // Every write keeps the index up to date.
+    store.set("carol".to_owned(), user("paris"))?;
+    store.set("alice".to_owned(), user("berlin"))?;
+    store.set_transactional(vec![("dave".to_owned(), user("berlin"))])?;
+    store.remove("bob".to_owned())?;
+    store.rename("carol".to_owned(), "erin".to_owned())?;
+    assert_eq!(store.query_secondary("city", "paris")?, vec!["erin"]);
+    assert_eq!(
+        store.query_secondary("city", "berlin")?,
+        vec!["alice", "dave"]
+    );
+
+    // The index is rebuilt from the logs after a refresh or reopening.
+    store.refresh()?;
+    assert_eq!(store.query_secondary("city", "paris")?, vec!["erin"]);
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.create_secondary_index("city", city)?;
+    assert_eq!(
+        store.query_secondary("city", "berlin")?,
+        vec!["alice", "dave"]
+    );
+    store.clear()?;
+    assert!(store.query_secondary("city", "berlin")?.is_empty());
+    Ok(())
+}
+
+// A store opened with a key filter should only index and show the matching keys.
+#[test]
+fn key_filter_partial_load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("user:{}", key_id), format!("value{}", key_id))?;
+        store.set(format!("order:{}", key_id), format!("value{}", key_id))?;
+    }
+    store.set_transactional(vec![
+        ("user:10".to_owned(), "value10".to_owned()),
+        ("order:10".to_owned(), "value10".to_owned()),
+    ])?;
+    drop(store);
+
+    let options = KvStoreOptions::new().key_filter("user:*");
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.len(), 11);
+    assert_eq!(store.get("user:10".to_owned())?, Some("value10".to_owned()));
+    assert_eq!(store.get("order:1".to_owned())?, None);
+    assert!(store
+        .iter()
+        .all(|entry| entry.is_ok_and(|(key, _)| key.starts_with("user:"))));
+    assert_eq!(
+        store.set("user:11".to_owned(), "value11".to_owned()),
+        Err(KvsError::ReadOnly)
+    );
+    assert_eq!(store.compact(), Err(KvsError::ReadOnly));
+    drop(store);
+
+    // Nothing was lost for a store opened without a filter.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 22);
+    Ok(())
+}
+
+// Log files should start with a header, and files of an unknown format should be rejected.
+#[test]
+fn log_file_header() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let header = r#"{"kvs_log":1,"flags":0}"#;
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(
+        store.locate("key1").map(|(_, pos, _)| pos),
+        Some(header.len() as u64)
+    );
+    drop(store);
+    let log_path = temp_dir.path().join("1.log");
+    let log = std::fs::read_to_string(&log_path)?;
+    assert!(log.starts_with(header));
+    let with_header = |new: &str| format!("{}{}", new, &log[header.len()..]);
+
+    // Another version.
+    std::fs::write(&log_path, with_header(r#"{"kvs_log":2,"flags":0}"#))?;
+    assert_eq!(
+        KvStore::open(temp_dir.path()).err(),
+        Some(KvsError::UnsupportedFormat { version: 2 })
+    );
+
+    // Not JSON at all.
+    std::fs::write(&log_path, with_header("KVSL"))?;
+    assert_eq!(
+        KvStore::open(temp_dir.path()).err(),
+        Some(KvsError::UnsupportedFormat { version: 0 })
+    );
+    assert_eq!(
+        scan_raw(temp_dir.path())?.next().unwrap().err(),
+        Some(KvsError::UnsupportedFormat { version: 0 })
+    );
+
+    // Flags this version doesn't know.
+    std::fs::write(&log_path, with_header(r#"{"kvs_log":1,"flags":128}"#))?;
+    assert_eq!(
+        KvStore::open(temp_dir.path()).err(),
+        Some(KvsError::UnsupportedFormat { version: 1 })
+    );
+
+    // An encrypted log opened without a key.
+    std::fs::write(&log_path, with_header(r#"{"kvs_log":1,"flags":1}"#))?;
+    assert_eq!(
+        KvStore::open(temp_dir.path()).err(),
+        Some(KvsError::ConfigMismatch {
+            setting: "encryption".to_owned()
+        })
+    );
+
+    // A plain log opened with a key.
+    #[cfg(feature = "encryption")]
+    {
+        std::fs::write(&log_path, &log)?;
+        let options = KvStoreOptions::new().encryption_key([7; 32]);
+        assert_eq!(
+            KvStore::open_with_options(temp_dir.path(), options).err(),
+            Some(KvsError::ConfigMismatch {
+                setting: "encryption".to_owned()
+            })
+        );
+    }
+
+    // A file written before the header still opens.
+    std::fs::write(&log_path, with_header(""))?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A store written with direct I/O should read back everything after reopening.
+#[cfg(all(feature = "direct-io", target_os = "linux"))]
+#[test]
+fn direct_io_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions::new().direct_io(true);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    let value = |key_id: usize| format!("value{}", key_id).repeat(key_id % 700 + 1);
+    for key_id in 0..2000 {
+        store.set(format!("key{}", key_id), value(key_id))?;
+    }
+    let batch = (2000..2100)
+        .map(|key_id| (format!("key{}", key_id), value(key_id)))
+        .collect();
+    store.set_transactional(batch)?;
+    for key_id in (0..2100).step_by(7) {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value(key_id)));
+    }
+    store.compact()?;
+    store.set("key0".to_owned(), "after compaction".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(
+        store.get("key0".to_owned())?,
+        Some("after compaction".to_owned())
+    );
+    for key_id in 1..2100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value(key_id)));
+    }
+    Ok(())
+}
+
+// Without direct I/O support, opening a store asking for it should fail.
+#[cfg(not(all(feature = "direct-io", target_os = "linux")))]
+#[test]
+fn direct_io_unsupported() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().direct_io(true);
+    assert_eq!(
+        KvStore::open_with_options(temp_dir.path(), options).err(),
+        Some(KvsError::DirectIoUnsupported)
+    );
+}
+
+// Dropping a generation should remove its file and keep its live keys readable.
+#[test]
+fn drop_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key in &["key1", "key2", "key3"] {
+        store.set(key.to_string(), "old".to_owned())?;
+    }
+    let gen = store.rotate()?;
+    store.set("key1".to_owned(), "new".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key4".to_owned(), "new".to_owned())?;
+    let active = store.rotate()?;
+    store.set("key5".to_owned(), "new".to_owned())?;
+
+    store.drop_generation(gen)?;
+    assert!(!temp_dir.path().join(format!("{}.log", gen)).exists());
+    assert!(!store.generations().contains(&gen));
+    let check = |store: &mut KvStore| -> Result<()> {
+        assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+        // The tombstone moved along, the older generation still holds the key.
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(store.get("key3".to_owned())?, Some("old".to_owned()));
+        assert_eq!(store.get("key4".to_owned())?, Some("new".to_owned()));
+        assert_eq!(store.get("key5".to_owned())?, Some("new".to_owned()));
+        Ok(())
+    };
+    check(&mut store)?;
+
+    assert_eq!(
+        store.drop_generation(active),
+        Err(KvsError::DropActiveGeneration)
+    );
+    assert_eq!(
+        store.drop_generation(gen),
+        Err(KvsError::GenerationNotFound(gen))
+    );
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    check(&mut store)?;
+    Ok(())
+}
+
+// A transforming compaction should leave only the transformed entries behind.
+#[test]
+fn compact_with_transform() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        store.set(format!("key{}", key_id), format!("value{}", key_id + 1))?;
+    }
+    store.set("drop".to_owned(), "me".to_owned())?;
+
+    store.compact_with_transform(|key, value| {
+        if key == "drop" {
+            None
+        } else {
+            Some((format!("v2:{}", key), value.to_uppercase()))
+        }
+    })?;
+    let check = |store: &mut KvStore| -> Result<()> {
+        assert_eq!(store.len(), 50);
+        for entry in store.iter() {
+            let (key, _) = entry?;
+            assert!(key.starts_with("v2:key"));
+        }
+        for key_id in 0..50 {
+            assert_eq!(store.get(format!("key{}", key_id))?, None);
+            assert_eq!(
+                store.get(format!("v2:key{}", key_id))?,
+                Some(format!("VALUE{}", key_id + 1))
+            );
+        }
+        assert_eq!(store.get("drop".to_owned())?, None);
+        Ok(())
+    };
+    check(&mut store)?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    check(&mut store)?;
+    Ok(())
+}
+
+// A crash before a transforming compaction removes the old logs should not bring back
+// the keys it dropped or renamed, and the compaction should be reported to the callbacks.
+#[test]
+fn compact_with_transform_crash() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let ends = Arc::new(AtomicUsize::new(0));
+    let options = {
+        let ends = ends.clone();
+        KvStoreOptions::new().on_compaction_end(move |_| {
+            ends.fetch_add(1, Ordering::SeqCst);
+        })
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.set("drop".to_owned(), "me".to_owned())?;
+    let old_logs = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| {
+            let path = entry?.path();
+            let contents = std::fs::read(&path)?;
+            Ok((path, contents))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    store.compact_with_transform(|key, value| match key {
+        "drop" => None,
+        "key0" => Some(("key0".to_owned(), value)),
+        _ => Some((format!("v2:{}", key), value)),
+    })?;
+    assert_eq!(ends.load(Ordering::SeqCst), 1);
+    drop(store);
+
+    // The old logs are left in place, as if the compaction crashed before removing them.
+    for (path, contents) in old_logs {
+        std::fs::write(path, contents)?;
+    }
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 10);
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    for key_id in 1..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+        assert_eq!(
+            store.get(format!("v2:key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    assert_eq!(store.get("drop".to_owned())?, None);
+    Ok(())
+}